@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+/// Reads from a small set of hot keys among many keys, with and without the cache.
+fn hot_get_bench(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "hot_get_bench",
+        |b, &cache_capacity| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new().cache_capacity(cache_capacity);
+            let store =
+                KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options).unwrap();
+            for i in 1..(1 << 14) {
+                block_on(store.set(format!("key{}", i).into_bytes(), vec![b'v'; 256])).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                block_on(store.get(format!("key{}", rng.gen_range(1, 1 << 8)).into_bytes()))
+                    .unwrap();
+            })
+        },
+        vec![0, 8 * 1024 * 1024],
+    );
+}
+
+criterion_group!(benches, hot_get_bench);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use futures::executor::block_on;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+const COMPRESSIONS: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Zstd];
+
+/// A JSON blob like the ones users store, which compresses well.
+fn value(i: u32) -> Vec<u8> {
+    format!(
+        "{{\"id\":{},\"name\":\"user{}\",\"tags\":[{}],\"active\":true}}",
+        i,
+        i,
+        "\"tag\",".repeat(64)
+    )
+    .into_bytes()
+}
+
+fn open(compression: Compression) -> (KvStore<RayonThreadPool>, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().compression(compression);
+    let store = KvStore::open_with_options(temp_dir.path(), 1, options).unwrap();
+    (store, temp_dir)
+}
+
+fn set_bench(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "set_bench",
+        |b, &compression| {
+            b.iter_batched(
+                || open(compression),
+                |(store, _temp_dir)| {
+                    for i in 1..(1 << 10) {
+                        block_on(store.set(format!("key{}", i).into_bytes(), value(i))).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        COMPRESSIONS.to_vec(),
+    );
+}
+
+fn get_bench(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "get_bench",
+        |b, &compression| {
+            let (store, _temp_dir) = open(compression);
+            for i in 1..(1 << 12) {
+                block_on(store.set(format!("key{}", i).into_bytes(), value(i))).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                block_on(store.get(format!("key{}", rng.gen_range(1, 1 << 12)).into_bytes()))
+                    .unwrap();
+            })
+        },
+        COMPRESSIONS.to_vec(),
+    );
+}
+
+fn compact_bench(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "compact_bench",
+        |b, &compression| {
+            b.iter_batched(
+                || {
+                    // written without compression, so the compaction compresses them
+                    let (store, temp_dir) = open(Compression::None);
+                    for i in 1..(1 << 10) {
+                        block_on(store.set(format!("key{}", i).into_bytes(), value(i))).unwrap();
+                    }
+                    drop(store);
+                    let options = KvStoreOptions::new().compression(compression);
+                    let store =
+                        KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)
+                            .unwrap();
+                    (store, temp_dir)
+                },
+                |(store, _temp_dir)| block_on(store.compact()).unwrap(),
+                BatchSize::SmallInput,
+            )
+        },
+        COMPRESSIONS.to_vec(),
+    );
+}
+
+criterion_group!(benches, set_bench, get_bench, compact_bench);
+criterion_main!(benches);
@@ -0,0 +1,371 @@
+#[macro_use]
+extern crate clap;
+
+use clap::AppSettings;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    KvPair, KvStore, KvStoreInspector, KvsEngine, KvsError, Result, SledKvsEngine, WriteBatch,
+};
+use log::LevelFilter;
+use std::env::current_dir;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::exit;
+use structopt::StructOpt;
+
+/// The number of pairs read or written at a time.
+const BATCH_SIZE: usize = 1000;
+
+/// The directory the new engine is written to during a migration.
+const MIGRATION_DIR: &str = "migrate.tmp";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "stats",
+        about = "Show the log files and the live and stale bytes of the kvs data \
+                 in the current directory"
+    )]
+    Stats,
+    #[structopt(
+        name = "dump",
+        about = "Print all live key/value pairs of the kvs data in the current directory"
+    )]
+    Dump {
+        #[structopt(
+            long,
+            help = "Sets the encoding of keys and values in the output",
+            value_name = "ENCODING",
+            default_value = "text",
+            raw(possible_values = "&Encoding::variants()")
+        )]
+        encoding: Encoding,
+    },
+    #[structopt(
+        name = "verify",
+        about = "Replay the log files of the kvs data in the current directory and \
+                 report corrupted records"
+    )]
+    Verify,
+    #[structopt(
+        name = "compact",
+        about = "Compact the log of the kvs data in the current directory. \
+                 The server must be stopped"
+    )]
+    Compact,
+    #[structopt(
+        name = "migrate",
+        about = "Migrate the data in the current directory to another engine. \
+                 The server must be stopped. Keys with a TTL keep the time they have \
+                 left, and expired keys are dropped"
+    )]
+    Migrate {
+        #[structopt(
+            long,
+            help = "Sets the engine of the current data",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        from: Engine,
+        #[structopt(
+            long,
+            help = "Sets the engine to migrate to",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to: Engine,
+    },
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Encoding {
+        text,
+        hex,
+        base64
+    }
+}
+
+impl Encoding {
+    /// Encodes a key or value for the output.
+    fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::text => bytes.to_vec(),
+            Encoding::hex => hex::encode(bytes).into_bytes(),
+            Encoding::base64 => base64::encode(bytes).into_bytes(),
+        }
+    }
+}
+
+impl Engine {
+    /// Returns whether a file in the data directory belongs to the engine.
+    fn owns(self, file_name: &str) -> bool {
+        match self {
+            Engine::kvs => {
+                file_name.ends_with(".log")
+                    || file_name.ends_with(".hint")
+                    || file_name.ends_with(".hint.tmp")
+            }
+            Engine::sled => {
+                file_name == "conf"
+                    || file_name == "db"
+                    || file_name == "blobs"
+                    || file_name.starts_with("snap.")
+            }
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::builder().filter_level(LevelFilter::Warn).init();
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt).await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let dir = current_dir()?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match opt.command {
+        Command::Stats => {
+            check_kvs_data(&dir)?;
+            let inspector = KvStoreInspector::open(&dir)?;
+            for stats in inspector.generations() {
+                writeln!(
+                    stdout,
+                    "{}.log: {:?}, {} bytes, {} live bytes{}",
+                    stats.gen,
+                    stats.format,
+                    stats.size,
+                    stats.live,
+                    if stats.has_hint { ", hint file" } else { "" }
+                )?;
+            }
+            writeln!(stdout, "keys: {}", inspector.snapshot().len()?)?;
+            writeln!(stdout, "live bytes: {}", inspector.live_bytes())?;
+            writeln!(stdout, "stale bytes: {}", inspector.stale_bytes())?;
+        }
+        Command::Dump { encoding } => {
+            check_kvs_data(&dir)?;
+            let snapshot = KvStoreInspector::open(&dir)?.snapshot();
+            for pair in snapshot.iter() {
+                let (key, value) = pair?;
+                stdout.write_all(&encoding.encode(&key))?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&encoding.encode(&value))?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        Command::Verify => {
+            check_kvs_data(&dir)?;
+            let inspector = KvStoreInspector::open(&dir)?;
+            for record in inspector.corrupted() {
+                writeln!(
+                    stdout,
+                    "{}.log at offset {}: {}",
+                    record.gen, record.offset, record.reason
+                )?;
+            }
+            if !inspector.corrupted().is_empty() {
+                return Err(KvsError::StringError(format!(
+                    "Found {} corrupted records",
+                    inspector.corrupted().len()
+                )));
+            }
+            writeln!(
+                stdout,
+                "Verified {} log files",
+                inspector.generations().len()
+            )?;
+        }
+        Command::Compact => {
+            check_kvs_data(&dir)?;
+            let store = KvStore::<RayonThreadPool>::open(&dir, 1)?;
+            let stale = store.compaction_stats().uncompacted;
+            store.compact().await?;
+            writeln!(stdout, "Compacted {} stale bytes", stale)?;
+        }
+        Command::Migrate { from, to } => {
+            let copied = migrate(&dir, from, to).await?;
+            writeln!(stdout, "Migrated {} pairs from {} to {}", copied, from, to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Migrates the data in `dir` from one engine to another.
+///
+/// The new engine is written to a temporary directory first. After the number of
+/// pairs is checked, the old data is moved to `<from>.old` and the new data takes its
+/// place.
+async fn migrate(dir: &Path, from: Engine, to: Engine) -> Result<usize> {
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "The data is already of the {} engine",
+            to
+        )));
+    }
+    if let Some(engine) = current_engine(dir)? {
+        if engine != from {
+            return Err(KvsError::StringError(format!(
+                "The data is of the {} engine",
+                engine
+            )));
+        }
+    }
+    let tmp_dir = dir.join(MIGRATION_DIR);
+    let old_dir = dir.join(format!("{}.old", from));
+    for leftover in &[&tmp_dir, &old_dir] {
+        if leftover.exists() {
+            return Err(KvsError::StringError(format!(
+                "{:?} is left by an earlier migration; remove it first",
+                leftover
+            )));
+        }
+    }
+
+    let concurrency = num_cpus::get() as u32;
+    let copied = match to {
+        Engine::kvs => {
+            copy_pairs(
+                SledKvsEngine::<RayonThreadPool>::open(dir, concurrency)?,
+                KvStore::<RayonThreadPool>::open(&tmp_dir, concurrency)?,
+            )
+            .await?
+        }
+        Engine::sled => {
+            copy_pairs(
+                KvStore::<RayonThreadPool>::open(dir, concurrency)?,
+                SledKvsEngine::<RayonThreadPool>::open(&tmp_dir, concurrency)?,
+            )
+            .await?
+        }
+    };
+
+    fs::create_dir(&old_dir)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if from.owns(&entry.file_name().to_string_lossy()) {
+            fs::rename(entry.path(), old_dir.join(entry.file_name()))?;
+        }
+    }
+    for entry in fs::read_dir(&tmp_dir)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&tmp_dir)?;
+    fs::write(dir.join("engine"), format!("{}", to))?;
+    Ok(copied)
+}
+
+/// Copies all pairs from `src` to `dst` and checks that `dst` has as many pairs.
+///
+/// Keys with a TTL are set with the time they have left, and keys which expire
+/// while they are copied are skipped. Returns the number of copied pairs.
+async fn copy_pairs<S: KvsEngine, D: KvsEngine>(src: S, dst: D) -> Result<usize> {
+    let mut copied = 0;
+    // keys with a TTL, which may expire before they are counted
+    let mut expiring = 0;
+    let mut start = Vec::new();
+    loop {
+        let pairs = scan_page(&src, &mut start).await?;
+        if pairs.is_empty() {
+            break;
+        }
+        let mut batch = WriteBatch::new();
+        for (key, _) in pairs {
+            match src.get_with_ttl(key.clone()).await? {
+                Some((value, None)) => {
+                    batch.set(key, value);
+                }
+                Some((value, Some(ttl))) => {
+                    dst.set_with_ttl(key, value, ttl).await?;
+                    expiring += 1;
+                }
+                None => continue,
+            }
+            copied += 1;
+        }
+        dst.write_batch(batch).await?;
+    }
+
+    let mut count = 0;
+    let mut start = Vec::new();
+    loop {
+        let pairs = scan_page(&dst, &mut start).await?;
+        if pairs.is_empty() {
+            break;
+        }
+        count += pairs.len();
+    }
+    if count > copied || count + expiring < copied {
+        return Err(KvsError::StringError(format!(
+            "Copied {} pairs, but the new engine has {} pairs",
+            copied, count
+        )));
+    }
+    Ok(copied)
+}
+
+/// Scans the next page of at most `BATCH_SIZE` pairs from `start` and moves `start`
+/// past the page.
+async fn scan_page<E: KvsEngine>(engine: &E, start: &mut Vec<u8>) -> Result<Vec<KvPair>> {
+    let pairs = engine.scan(start.clone(), None, Some(BATCH_SIZE)).await?;
+    if let Some((key, _)) = pairs.last() {
+        // the smallest key after the last one
+        *start = [&key[..], &[0]].concat();
+    }
+    Ok(pairs)
+}
+
+/// Checks that the data in the directory is not of an engine other than kvs.
+fn check_kvs_data(dir: &Path) -> Result<()> {
+    match current_engine(dir)? {
+        Some(Engine::sled) => Err(KvsError::StringError(
+            "The data is of the sled engine".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+
+    match fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => Err(KvsError::StringError(format!(
+            "The content of engine file is invalid: {}",
+            e
+        ))),
+    }
+}
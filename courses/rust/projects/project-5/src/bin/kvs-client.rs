@@ -1,9 +1,14 @@
+#[macro_use]
+extern crate clap;
+
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{ClientTls, Histogram, KvsClient, KvsClientOptions, KvsError, Result, ServerStats};
+use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::prelude::*;
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -15,13 +20,38 @@ use tokio::prelude::*;
 struct Opt {
     #[structopt(subcommand)]
     command: Command,
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects with TLS, trusting the certificates in the given PEM file",
+        value_name = "FILE",
+        raw(global = "true"),
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long = "tls-domain",
+        help = "Sets the name the server certificate must be valid for instead of \
+                the server address",
+        value_name = "NAME",
+        requires = "tls_ca",
+        raw(global = "true")
+    )]
+    tls_domain: Option<String>,
+    #[structopt(
+        long = "auth-token",
+        help = "Authenticates with the given token",
+        value_name = "TOKEN",
+        env = "KVS_AUTH_TOKEN",
+        raw(global = "true", hide_env_values = "true")
+    )]
+    auth_token: Option<String>,
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
-    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    #[structopt(name = "get", about = "Get the value of a given key")]
     Get {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
         #[structopt(
             long,
@@ -31,13 +61,27 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Sets the encoding of keys and values in the arguments and the output",
+            value_name = "ENCODING",
+            default_value = "text",
+            raw(possible_values = "&Encoding::variants()")
+        )]
+        encoding: Encoding,
     },
-    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    #[structopt(name = "set", about = "Set the value of a key")]
     Set {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
-        #[structopt(name = "VALUE", help = "The string value of the key")]
+        #[structopt(name = "VALUE", help = "The value of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Expires the key after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -46,10 +90,18 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Sets the encoding of keys and values in the arguments and the output",
+            value_name = "ENCODING",
+            default_value = "text",
+            raw(possible_values = "&Encoding::variants()")
+        )]
+        encoding: Encoding,
     },
-    #[structopt(name = "rm", about = "Remove a given string key")]
+    #[structopt(name = "rm", about = "Remove a given key")]
     Remove {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
         #[structopt(
             long,
@@ -59,37 +111,316 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Sets the encoding of keys and values in the arguments and the output",
+            value_name = "ENCODING",
+            default_value = "text",
+            raw(possible_values = "&Encoding::variants()")
+        )]
+        encoding: Encoding,
+    },
+    #[structopt(
+        name = "scan",
+        about = "Scan key/value pairs in a range of keys or with a key prefix"
+    )]
+    Scan {
+        #[structopt(name = "START", help = "The first key of the range")]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The end of the range, exclusive")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Scans keys with the given prefix instead of a range",
+            value_name = "PREFIX",
+            conflicts_with = "START"
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the maximum number of pairs to return",
+            value_name = "N"
+        )]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Sets the encoding of keys and values in the arguments and the output",
+            value_name = "ENCODING",
+            default_value = "text",
+            raw(possible_values = "&Encoding::variants()")
+        )]
+        encoding: Encoding,
+    },
+    #[structopt(
+        name = "backup",
+        about = "Back up the store of the server to a directory on the server host"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            help = "The directory to write the backup to, relative to the backup root of \
+                    the server. It must not contain data",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
     },
+    #[structopt(name = "stats", about = "Show the statistics of the server")]
+    Stats {
+        #[structopt(
+            long,
+            help = "Sets the output format",
+            value_name = "FORMAT",
+            default_value = "text",
+            raw(possible_values = "&StatsFormat::variants()")
+        )]
+        format: StatsFormat,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum StatsFormat {
+        text,
+        json,
+        prometheus
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Encoding {
+        text,
+        hex,
+        base64
+    }
+}
+
+impl Encoding {
+    /// Decodes a key or value in the arguments.
+    fn decode(self, input: String) -> Result<Vec<u8>> {
+        let res = match self {
+            Encoding::text => return Ok(input.into_bytes()),
+            Encoding::hex => hex::decode(&input).map_err(|e| e.to_string()),
+            Encoding::base64 => base64::decode(&input).map_err(|e| e.to_string()),
+        };
+        res.map_err(|e| KvsError::StringError(format!("Invalid {} input {}: {}", self, input, e)))
+    }
+
+    /// Encodes a key or value for the output.
+    fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::text => bytes.to_vec(),
+            Encoding::hex => hex::encode(bytes).into_bytes(),
+            Encoding::base64 => base64::encode(bytes).into_bytes(),
+        }
+    }
 }
 
-fn main() {
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
     let opt = Opt::from_args();
-    if let Err(e) = run(opt) {
+    if let Err(e) = run(opt).await {
         eprintln!("{}", e);
         exit(1);
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
+/// Returns the connection options in the arguments.
+fn client_options(opt: &Opt) -> Result<KvsClientOptions> {
+    let mut options = KvsClientOptions::new();
+    if let Some(ca) = &opt.tls_ca {
+        let mut tls = ClientTls::from_ca_file(ca)?;
+        if let Some(domain) = &opt.tls_domain {
+            tls = tls.domain(domain)?;
+        }
+        options = options.tls(tls);
+    }
+    if let Some(token) = &opt.auth_token {
+        options = options.auth_token(token.clone());
+    }
+    Ok(options)
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let options = client_options(&opt)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
     match opt.command {
-        Command::Get { key, addr } => {
-            let client = KvsClient::connect(addr);
-            if let (Some(value), _) = client.and_then(move |client| client.get(key)).wait()? {
-                println!("{}", value);
+        Command::Get {
+            key,
+            addr,
+            encoding,
+        } => {
+            let key = encoding.decode(key)?;
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            if let Some(value) = client.get(key).await? {
+                stdout.write_all(&encoding.encode(&value))?;
+                stdout.write_all(b"\n")?;
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
-            let client = KvsClient::connect(addr);
-            client
-                .and_then(move |client| client.set(key, value))
-                .wait()?;
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+            encoding,
+        } => {
+            let key = encoding.decode(key)?;
+            let value = encoding.decode(value)?;
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            match ttl {
+                Some(ttl) => {
+                    client
+                        .set_with_ttl(key, value, Duration::from_secs(ttl))
+                        .await?
+                }
+                None => client.set(key, value).await?,
+            };
+        }
+        Command::Remove {
+            key,
+            addr,
+            encoding,
+        } => {
+            let key = encoding.decode(key)?;
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            client.remove(key).await?;
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+            encoding,
+        } => {
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            let pairs = match prefix {
+                Some(prefix) => {
+                    let prefix = encoding.decode(prefix)?;
+                    client.scan_prefix(prefix, limit).await?
+                }
+                None => {
+                    let start = encoding.decode(start.unwrap_or_default())?;
+                    let end = end.map(|end| encoding.decode(end)).transpose()?;
+                    client.scan(start, end, limit).await?
+                }
+            };
+            for (key, value) in pairs {
+                stdout.write_all(&encoding.encode(&key))?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&encoding.encode(&value))?;
+                stdout.write_all(b"\n")?;
+            }
         }
-        Command::Remove { key, addr } => {
-            let client = KvsClient::connect(addr);
-            client.and_then(move |client| client.remove(key)).wait()?;
+        Command::Backup { dir, addr } => {
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            client.backup(dir).await?;
         }
+        Command::Stats { format, addr } => {
+            let client = KvsClient::connect_with_options(addr, options).await?;
+            let stats = client.stats().await?;
+            match format {
+                StatsFormat::text => write_stats(&mut stdout, &stats)?,
+                StatsFormat::json => {
+                    serde_json::to_writer_pretty(&mut stdout, &stats)?;
+                    writeln!(stdout)?;
+                }
+                StatsFormat::prometheus => stdout.write_all(stats.to_prometheus().as_bytes())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the statistics for humans.
+fn write_stats(out: &mut impl Write, stats: &ServerStats) -> Result<()> {
+    writeln!(out, "uptime: {}s", stats.uptime_secs)?;
+    writeln!(
+        out,
+        "connections: {} ({} active)",
+        stats.connections, stats.active_connections
+    )?;
+    writeln!(
+        out,
+        "requests: {} ({} gets, {} sets, {} removes, {} scans, {} errors)",
+        stats.requests, stats.gets, stats.sets, stats.removes, stats.scans, stats.errors
+    )?;
+    writeln!(out, "latency: {}", summary(&stats.latency))?;
+
+    let engine = &stats.engine;
+    writeln!(out, "disk usage: {} bytes", engine.disk_usage)?;
+    let pool = &engine.thread_pool;
+    writeln!(
+        out,
+        "thread pool: {} threads, {} queued, {} running, {} completed",
+        pool.threads, pool.queued, pool.running, pool.completed
+    )?;
+    writeln!(out, "thread pool wait: {}", summary(&pool.wait))?;
+    if let Some(compaction) = &engine.compaction {
+        writeln!(
+            out,
+            "compaction: {} finished, {} failed, {} stale bytes{}",
+            compaction.finished,
+            compaction.failed,
+            compaction.uncompacted,
+            if compaction.running { ", running" } else { "" }
+        )?;
+        writeln!(out, "compaction time: {}", summary(&compaction.durations))?;
+    }
+    if let Some(cache) = &engine.cache {
+        writeln!(
+            out,
+            "cache: {} hits, {} misses, {} of {} bytes",
+            cache.hits, cache.misses, cache.size, cache.capacity
+        )?;
     }
     Ok(())
 }
+
+/// Summarizes a histogram of durations with the mean and the upper bounds of the
+/// median and the 99th percentile.
+fn summary(histogram: &Histogram) -> String {
+    let mean = match histogram.mean() {
+        Some(mean) => mean,
+        None => return "none".to_owned(),
+    };
+    let quantile = |q| match histogram.quantile(q) {
+        Some(bound) => format!("<= {:?}", bound),
+        None => "above all buckets".to_owned(),
+    };
+    format!(
+        "mean {:?}, p50 {}, p99 {}",
+        mean,
+        quantile(0.5),
+        quantile(0.99)
+    )
+}
@@ -4,14 +4,23 @@ extern crate log;
 extern crate clap;
 
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, Protocol, Result, ServerTls,
+    SledKvsEngine, SyncPolicy,
+};
 use log::LevelFilter;
-use std::env;
+use serde::{Deserialize, Deserializer};
 use std::env::current_dir;
+use std::fmt;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 use structopt::StructOpt;
+use tokio::runtime::Runtime;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -21,12 +30,19 @@ const DEFAULT_ENGINE: Engine = Engine::kvs;
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Reads the options from the given TOML file. The options in the \
+                arguments override the ones in the file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the listening address [default: 127.0.0.1:4000]",
         value_name = "IP:PORT",
-        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -34,6 +50,239 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long = "data-dir",
+        help = "Sets the directory of the data [default: the current directory]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the number of threads of the engine [default: the number of CPUs]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long = "thread-pool",
+        help = "Sets the thread pool of the engine: naive, shared-queue or rayon \
+                [default: rayon]",
+        value_name = "POOL",
+        parse(try_from_str)
+    )]
+    thread_pool: Option<ThreadPoolKind>,
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to the disk with the kvs engine: \
+                always, never, or an interval of group commits like 10ms [default: never]",
+        value_name = "POLICY",
+        parse(try_from_str)
+    )]
+    sync: Option<SyncPolicy>,
+    #[structopt(
+        long = "compaction-threshold",
+        help = "Compacts the log of the kvs engine when it has more than the given \
+                number of bytes of stale commands [default: 1048576]",
+        value_name = "BYTES"
+    )]
+    compaction_threshold: Option<u64>,
+    #[structopt(
+        long = "log-level",
+        help = "Sets the log level: off, error, warn, info, debug or trace [default: info]",
+        value_name = "LEVEL",
+        parse(try_from_str)
+    )]
+    log_level: Option<LevelFilter>,
+    #[structopt(
+        long = "restore-from",
+        help = "Restores the data from a backup made by `kvs-client backup` before \
+                starting. The data directory must not have data yet",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    restore_from: Option<PathBuf>,
+    #[structopt(
+        long = "backup-root",
+        help = "Lets `kvs-client backup` write backups to directories under the given \
+                one. Backups are refused without it",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    backup_root: Option<PathBuf>,
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves TLS with the certificate chain in the given PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "Sets the PEM file of the private key of the TLS certificate",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "auth-token",
+        help = "Requires clients to authenticate with the given token",
+        value_name = "TOKEN",
+        env = "KVS_AUTH_TOKEN",
+        raw(hide_env_values = "true")
+    )]
+    auth_token: Option<String>,
+    #[structopt(
+        long,
+        help = "Sets the protocol to speak: kvs for kvs-client, or resp for Redis clients \
+                [default: kvs]",
+        value_name = "PROTOCOL",
+        parse(try_from_str)
+    )]
+    protocol: Option<Protocol>,
+    #[structopt(
+        long = "http-addr",
+        help = "Also serves the HTTP API on the given address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+}
+
+/// The options in a config file, with the same names as the arguments.
+///
+/// Relative paths are relative to the directory of the file.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    addr: Option<SocketAddr>,
+    #[serde(default, deserialize_with = "from_str")]
+    engine: Option<Engine>,
+    data_dir: Option<PathBuf>,
+    threads: Option<u32>,
+    #[serde(default, deserialize_with = "from_str")]
+    thread_pool: Option<ThreadPoolKind>,
+    #[serde(default, deserialize_with = "from_str")]
+    sync: Option<SyncPolicy>,
+    compaction_threshold: Option<u64>,
+    #[serde(default, deserialize_with = "from_str")]
+    log_level: Option<LevelFilter>,
+    backup_root: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    auth_token: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    protocol: Option<Protocol>,
+    http_addr: Option<SocketAddr>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<ConfigFile> {
+        let content = fs::read_to_string(path)?;
+        let mut config: ConfigFile = toml::from_str(&content)
+            .map_err(|e| KvsError::StringError(format!("Invalid config file {:?}: {}", path, e)))?;
+        if let Some(dir) = path.parent() {
+            let mut paths = [
+                &mut config.data_dir,
+                &mut config.backup_root,
+                &mut config.tls_cert,
+                &mut config.tls_key,
+            ];
+            for path in paths.iter_mut().filter_map(|path| path.as_mut()) {
+                *path = dir.join(&path);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Deserializes an option from a string like in the arguments.
+fn from_str<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// The options of the server from the arguments and the config file.
+#[derive(Debug)]
+struct Config {
+    addr: SocketAddr,
+    // decided by the data in the data directory if not specified
+    engine: Option<Engine>,
+    data_dir: PathBuf,
+    threads: u32,
+    thread_pool: ThreadPoolKind,
+    sync: SyncPolicy,
+    compaction_threshold: Option<u64>,
+    log_level: LevelFilter,
+    restore_from: Option<PathBuf>,
+    backup_root: Option<PathBuf>,
+    tls: Option<(PathBuf, PathBuf)>,
+    auth_token: Option<String>,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Merges the arguments into the config file if one is given.
+    fn new(opt: Opt) -> Result<Config> {
+        let file = match &opt.config {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+        let threads = opt
+            .threads
+            .or(file.threads)
+            .unwrap_or_else(|| num_cpus::get() as u32);
+        if threads == 0 {
+            return Err(KvsError::StringError(
+                "The number of threads must be positive".to_owned(),
+            ));
+        }
+        let tls = match (opt.tls_cert.or(file.tls_cert), opt.tls_key.or(file.tls_key)) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(KvsError::StringError(
+                    "The TLS certificate and key must be set together".to_owned(),
+                ))
+            }
+        };
+        let data_dir = match opt.data_dir.or(file.data_dir) {
+            Some(data_dir) => data_dir,
+            None => current_dir()?,
+        };
+        Ok(Config {
+            addr: opt
+                .addr
+                .or(file.addr)
+                .unwrap_or_else(|| DEFAULT_LISTENING_ADDRESS.parse().unwrap()),
+            engine: opt.engine.or(file.engine),
+            data_dir,
+            threads,
+            thread_pool: opt
+                .thread_pool
+                .or(file.thread_pool)
+                .unwrap_or(ThreadPoolKind::Rayon),
+            sync: opt.sync.or(file.sync).unwrap_or(SyncPolicy::Never),
+            compaction_threshold: opt.compaction_threshold.or(file.compaction_threshold),
+            log_level: opt
+                .log_level
+                .or(file.log_level)
+                .unwrap_or(LevelFilter::Info),
+            restore_from: opt.restore_from,
+            backup_root: opt.backup_root.or(file.backup_root),
+            tls,
+            auth_token: opt.auth_token.or(file.auth_token),
+            protocol: opt.protocol.or(file.protocol).unwrap_or(Protocol::Kvs),
+            http_addr: opt.http_addr.or(file.http_addr),
+        })
+    }
 }
 
 arg_enum! {
@@ -45,18 +294,56 @@ arg_enum! {
     }
 }
 
+/// The thread pools the engine can run in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ThreadPoolKind {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ThreadPoolKind, String> {
+        match s {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared-queue" => Ok(ThreadPoolKind::SharedQueue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(format!("invalid thread pool: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ThreadPoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadPoolKind::Naive => write!(f, "naive"),
+            ThreadPoolKind::SharedQueue => write!(f, "shared-queue"),
+            ThreadPoolKind::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
+    let res = Config::new(Opt::from_args());
+    let log_level = match &res {
+        Ok(config) => config.log_level,
+        Err(_) => LevelFilter::Info,
+    };
+    env_logger::builder().filter_level(log_level).init();
+    let res = res.and_then(|mut config| {
+        fs::create_dir_all(&config.data_dir)?;
+        restore(&config)?;
+        let curr_engine = current_engine(&config.data_dir)?;
+        if config.engine.is_none() {
+            config.engine = curr_engine;
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
+        if curr_engine.is_some() && config.engine != curr_engine {
             error!("Wrong engine!");
             exit(1);
         }
-        run(opt)
+        run(config)
     });
     if let Err(e) = res {
         error!("{}", e);
@@ -64,38 +351,191 @@ fn main() {
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+fn run(config: Config) -> Result<()> {
+    let engine = config.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Data directory: {:?}", config.data_dir);
+    info!(
+        "Thread pool: {} with {} threads",
+        config.thread_pool, config.threads
+    );
+    info!("Listening on {}", config.addr);
+    info!("Protocol: {}", config.protocol);
+    if let Some(http_addr) = config.http_addr {
+        info!("HTTP API listening on {}", http_addr);
+    }
+    if engine == Engine::kvs {
+        info!("Sync policy: {}", config.sync);
+        if let Some(threshold) = config.compaction_threshold {
+            info!("Compaction threshold: {} bytes", threshold);
+        }
+    }
+    let tls = match &config.tls {
+        Some((cert, key)) => {
+            info!("TLS certificate: {:?}", cert);
+            Some(ServerTls::from_pem_files(cert, key)?)
+        }
+        None => None,
+    };
+    if config.auth_token.is_some() {
+        info!("Authentication required");
+    }
+    if let Some(backup_root) = &config.backup_root {
+        info!("Backup root: {:?}", backup_root);
+    }
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    fs::write(config.data_dir.join("engine"), format!("{}", engine))?;
 
-    let concurrency = num_cpus::get() as u32;
+    match config.thread_pool {
+        ThreadPoolKind::Naive => run_in::<NaiveThreadPool>(engine, config, tls),
+        ThreadPoolKind::SharedQueue => run_in::<SharedQueueThreadPool>(engine, config, tls),
+        ThreadPoolKind::Rayon => run_in::<RayonThreadPool>(engine, config, tls),
+    }
+}
+
+/// Opens the engine with the thread pool `P` and runs the server.
+fn run_in<P: ThreadPool>(engine: Engine, config: Config, tls: Option<ServerTls>) -> Result<()> {
     match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
-        ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
-                sled::Db::start_default(env::current_dir()?)?,
-                concurrency,
-            )?,
-            opt.addr,
-        ),
+        Engine::kvs => {
+            let mut options = KvStoreOptions::new().sync_policy(config.sync);
+            if let Some(threshold) = config.compaction_threshold {
+                options = options.compaction_threshold(threshold);
+            }
+            let store = KvStore::<P>::open_with_options(&config.data_dir, config.threads, options)?;
+            run_with(store, config, tls)
+        }
+        Engine::sled => {
+            let engine = SledKvsEngine::<P>::open(&config.data_dir, config.threads)?;
+            run_with(engine, config, tls)
+        }
     }
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+/// Runs the server until it receives SIGTERM or Ctrl-C.
+fn run_with<E: KvsEngine>(engine: E, config: Config, tls: Option<ServerTls>) -> Result<()> {
+    let mut server = KvsServer::new(engine).protocol(config.protocol);
+    if let Some(http_addr) = config.http_addr {
+        server = server.http(http_addr);
+    }
+    if let Some(tls) = tls {
+        server = server.tls(tls);
+    }
+    if let Some(token) = config.auth_token {
+        server = server.auth_token(token);
+    }
+    if let Some(backup_root) = config.backup_root {
+        server = server.backup_root(backup_root);
+    }
+    let addr = config.addr;
+    let handle = server.shutdown_handle();
+    Runtime::new()?.block_on(async move {
+        tokio::spawn(async move {
+            match shutdown_signal().await {
+                Ok(()) => handle.shutdown(),
+                Err(e) => error!("Error on listening for signals: {}", e),
+            }
+        });
+        server.run(addr).await
+    })
+}
+
+/// Waits for SIGTERM or Ctrl-C.
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        res = tokio::signal::ctrl_c() => Ok(res?),
+    }
+}
+
+/// Waits for Ctrl-C.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+/// Copies the backup in `config.restore_from` to the data directory if specified.
+fn restore(config: &Config) -> Result<()> {
+    let backup_dir = match config.restore_from {
+        Some(ref backup_dir) => backup_dir,
+        None => return Ok(()),
+    };
+    let data_dir = &config.data_dir;
+    if has_data(data_dir)? {
+        return Err(KvsError::StringError(format!(
+            "Cannot restore into {:?}, which already has data",
+            data_dir
+        )));
+    }
+    let engine = backup_engine(backup_dir)?;
+    if config.engine.is_some() && config.engine != Some(engine) {
+        return Err(KvsError::StringError(format!(
+            "The backup is made by the {} engine",
+            engine
+        )));
+    }
+
+    info!("Restoring {} data from {:?}", engine, backup_dir);
+    copy_dir(backup_dir, data_dir)?;
+    fs::write(data_dir.join("engine"), format!("{}", engine))?;
+    Ok(())
+}
+
+/// Returns the engine which made the backup in the given directory.
+fn backup_engine(dir: &Path) -> Result<Engine> {
+    for entry in fs::read_dir(dir)? {
+        if entry?.path().extension() == Some("log".as_ref()) {
+            return Ok(Engine::kvs);
+        }
+    }
+    if dir.join("conf").exists() {
+        return Ok(Engine::sled);
+    }
+    Err(KvsError::StringError(format!("{:?} is not a backup", dir)))
+}
+
+/// Returns whether the directory has files of either engine, even without the
+/// `engine` file.
+fn has_data(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let name = path.file_name().and_then(|name| name.to_str());
+        let data = match (name, extension) {
+            // the kvs engine, including temporary hint files
+            (_, Some("log")) | (_, Some("hint")) | (_, Some("tmp")) => true,
+            // the sled engine
+            (Some("conf"), _) | (Some("db"), _) | (Some("blobs"), _) => true,
+            (Some(name), _) => name == "engine" || name.starts_with("snap."),
+            (None, _) => false,
+        };
+        if data {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+/// Returns the engine of the data in the data directory, if any.
+fn current_engine(data_dir: &Path) -> Result<Option<Engine>> {
+    let engine = data_dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
@@ -1,88 +1,428 @@
-use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::common::{Frame, Request, Response};
+use crate::{ClientTls, KvPair, KvsError, Result, ServerStats, WriteBatch};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::io::{ReadHalf, WriteHalf};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+use tokio::sync::{self, mpsc, oneshot};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// The default number of connections of a `KvsClient`.
+const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Options of a `KvsClient`.
+#[derive(Clone)]
+pub struct KvsClientOptions {
+    pool_size: usize,
+    tls: Option<ClientTls>,
+    auth_token: Option<String>,
+}
+
+impl Default for KvsClientOptions {
+    fn default() -> KvsClientOptions {
+        KvsClientOptions {
+            pool_size: DEFAULT_POOL_SIZE,
+            tls: None,
+            auth_token: None,
+        }
+    }
+}
+
+impl fmt::Debug for KvsClientOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the token is a secret
+        f.debug_struct("KvsClientOptions")
+            .field("pool_size", &self.pool_size)
+            .field("tls", &self.tls)
+            .field("auth_token", &self.auth_token.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl KvsClientOptions {
+    /// Creates the default options.
+    pub fn new() -> KvsClientOptions {
+        KvsClientOptions::default()
+    }
+
+    /// Sets the maximum number of connections to the server, which are opened
+    /// when they are first needed. The default is 4.
+    pub fn pool_size(mut self, pool_size: usize) -> KvsClientOptions {
+        self.pool_size = pool_size.max(1);
+        self
+    }
+
+    /// Connects to the server with TLS.
+    pub fn tls(mut self, tls: ClientTls) -> KvsClientOptions {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates every connection with the given token, which the server
+    /// may require before any request.
+    pub fn auth_token(mut self, token: String) -> KvsClientOptions {
+        self.auth_token = Some(token);
+        self
+    }
+}
 
 /// Key value store client
+///
+/// Clones of a client share a pool of connections to the server. Requests are
+/// pipelined: a connection carries many requests at a time without waiting for
+/// their responses, which are matched to the requests by IDs. So a client can be
+/// shared by many tasks.
+///
+/// The connections are driven by tasks on the tokio runtime the client is
+/// created in. A broken connection is replaced by a new one on the next request.
+#[derive(Clone)]
 pub struct KvsClient {
-    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
-    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    pool: Arc<Pool>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
-    pub fn connect(addr: SocketAddr) -> impl Future<Item = Self, Error = KvsError> {
-        TcpStream::connect(&addr)
-            .map(|tcp| {
-                let (read_half, write_half) = tcp.split();
-                let read_json =
-                    ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-                let write_json =
-                    WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-                KvsClient {
-                    read_json,
-                    write_json,
-                }
-            })
-            .map_err(|e| e.into())
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        KvsClient::connect_with_options(addr, KvsClientOptions::new()).await
+    }
+
+    /// Connect to `addr` to access `KvsServer` with the given options.
+    ///
+    /// The first connection is opened immediately, so an unreachable server, a
+    /// TLS failure or a rejected token is reported here.
+    pub async fn connect_with_options(addr: SocketAddr, options: KvsClientOptions) -> Result<Self> {
+        let pool = Pool {
+            addr,
+            tls: options.tls,
+            auth_token: options.auth_token,
+            slots: (0..options.pool_size)
+                .map(|_| sync::Mutex::new(None))
+                .collect(),
+            next: AtomicUsize::new(0),
+        };
+        pool.connection().await?;
+        Ok(KvsClient {
+            pool: Arc::new(pool),
+        })
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
-        self.send_request(Request::Get { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Get(value)) => Ok((value, client)),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
-            })
-    }
-
-    /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Set { key, value })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Set) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
-            })
-    }
-
-    /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Remove { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Remove) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
-            })
-    }
-
-    fn send_request(
-        self,
-        req: Request,
-    ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
-        let read_json = self.read_json;
-        self.write_json
-            .send(req)
-            .and_then(move |write_json| {
-                read_json
-                    .into_future()
-                    .map(move |(resp, read_json)| {
-                        let client = KvsClient {
-                            read_json,
-                            write_json,
-                        };
-                        (resp, client)
-                    })
-                    .map_err(|(err, _)| err)
-            })
-            .map_err(|e| e.into())
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.send_request(Request::Get { key }).await? {
+            Response::Get(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set the value of a key in the server.
+    pub async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_set(Request::Set {
+            key,
+            value,
+            ttl: None,
+        })
+        .await
+    }
+
+    /// Set the value of a key in the server which expires after `ttl`.
+    pub async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.send_set(Request::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        })
+        .await
+    }
+
+    async fn send_set(&self, request: Request) -> Result<()> {
+        match self.send_request(request).await? {
+            Response::Set => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a key in the server.
+    pub async fn remove(&self, key: Vec<u8>) -> Result<()> {
+        match self.send_request(Request::Remove { key }).await? {
+            Response::Remove => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Apply all operations in a batch atomically in the server.
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        match self.send_request(Request::WriteBatch(batch)).await? {
+            Response::WriteBatch => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set or remove a key in the server if its current value is `expected`.
+    ///
+    /// It fails with `KvsError::ConditionFailed` if the condition is not met.
+    pub async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        match self
+            .send_request(Request::CompareAndSwap { key, expected, new })
+            .await?
+        {
+            Response::CompareAndSwap => Ok(()),
+            Response::ConditionFailed => Err(KvsError::ConditionFailed),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the key/value pairs with keys in the range `[start, end)` from the server.
+    ///
+    /// If `end` is `None`, the range is unbounded.
+    pub async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KvPair>> {
+        match self
+            .send_request(Request::Scan { start, end, limit })
+            .await?
+        {
+            Response::Scan(pairs) => Ok(pairs),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the key/value pairs whose keys start with `prefix` from the server.
+    pub async fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        match self
+            .send_request(Request::ScanPrefix { prefix, limit })
+            .await?
+        {
+            Response::Scan(pairs) => Ok(pairs),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Back up the store to `dir` on the server host while the server keeps serving.
+    ///
+    /// `dir` is a relative path under the backup root of the server, which must
+    /// be set for the server to accept backups.
+    pub async fn backup(&self, dir: PathBuf) -> Result<()> {
+        match self.send_request(Request::Backup { dir }).await? {
+            Response::Backup => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the statistics of the server and its storage engine.
+    pub async fn stats(&self) -> Result<ServerStats> {
+        match self.send_request(Request::Stats).await? {
+            Response::Stats(stats) => Ok(*stats),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    async fn send_request(&self, req: Request) -> Result<Response> {
+        self.pool.connection().await?.send(req).await
+    }
+}
+
+/// The connections of the clones of a client.
+struct Pool {
+    addr: SocketAddr,
+    tls: Option<ClientTls>,
+    auth_token: Option<String>,
+    slots: Vec<sync::Mutex<Option<Arc<Connection>>>>,
+    // the slot of the next request
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// Returns the next connection in turn, opening it if it's not open yet or
+    /// has been closed.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut slot = self.slots[i].lock().await;
+        match &*slot {
+            Some(conn) if !conn.is_closed() => Ok(Arc::clone(conn)),
+            _ => {
+                let conn = Connection::open(self.addr, self.tls.as_ref()).await?;
+                if let Some(token) = &self.auth_token {
+                    conn.authenticate(token.clone()).await?;
+                }
+                let conn = Arc::new(conn);
+                *slot = Some(Arc::clone(&conn));
+                Ok(conn)
+            }
+        }
+    }
+}
+
+/// The senders of the responses to the requests waiting on a connection.
+///
+/// It's `None` after the connection is closed, which drops the senders.
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
+
+/// A connection to the server, which is written by one task and read by another.
+struct Connection {
+    // frames to write
+    frames: mpsc::UnboundedSender<Bytes>,
+    pending: Pending,
+    next_id: AtomicU64,
+}
+
+impl Connection {
+    async fn open(addr: SocketAddr, tls: Option<&ClientTls>) -> Result<Connection> {
+        let tcp = TcpStream::connect(addr).await?;
+        match tls {
+            Some(tls) => {
+                let stream = tls.connector().connect(tls.server_name(addr), tcp).await?;
+                Ok(Connection::start(stream))
+            }
+            None => Ok(Connection::start(tcp)),
+        }
+    }
+
+    /// Starts the tasks to write and read the stream.
+    fn start<S>(stream: S) -> Connection
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (writer_done, writer_stopped) = oneshot::channel();
+        tokio::spawn(write_requests(
+            write_half,
+            rx,
+            Arc::clone(&pending),
+            writer_done,
+        ));
+        tokio::spawn(read_responses(
+            read_half,
+            Arc::clone(&pending),
+            writer_stopped,
+        ));
+        Connection {
+            frames: tx,
+            pending,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Authenticates the connection, which must be done before other requests.
+    async fn authenticate(&self, token: String) -> Result<()> {
+        match self.send(Request::Auth { token }).await? {
+            Response::Auth => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Sends a request and waits for its response.
+    async fn send(&self, req: Request) -> Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Bytes::from(serde_json::to_vec(&Frame { id, body: req })?);
+        let (tx, rx) = oneshot::channel();
+        match &mut *self.pending.lock().unwrap() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(no_response()),
+        };
+        // a closed writer has closed `pending` as well
+        let _ = self.frames.send(frame);
+        rx.await.map_err(|_| no_response())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.frames.is_closed() || self.pending.lock().unwrap().is_none()
+    }
+}
+
+/// Writes the frames of requests in order until the connection is dropped or broken.
+///
+/// The stream is shut down when the connection is dropped, so the server closes
+/// its side as well. `_done` is dropped when the writer stops.
+async fn write_requests<S: AsyncWrite>(
+    write_half: WriteHalf<S>,
+    mut requests: mpsc::UnboundedReceiver<Bytes>,
+    pending: Pending,
+    _done: oneshot::Sender<()>,
+) {
+    let mut frames = FramedWrite::new(write_half, LengthDelimitedCodec::new());
+    while let Some(frame) = requests.recv().await {
+        // requests which are sent together are flushed together
+        let res = if requests.is_empty() {
+            frames.send(frame).await
+        } else {
+            frames.feed(frame).await
+        };
+        if let Err(e) = res {
+            error!("Error on sending requests: {}", e);
+            pending.lock().unwrap().take();
+            return;
+        }
+    }
+    if let Err(e) = SinkExt::<Bytes>::close(&mut frames).await {
+        error!("Error on closing connection: {}", e);
+    }
+}
+
+/// Passes responses to the waiting requests until the server closes the connection
+/// or the writer stops.
+async fn read_responses<S: AsyncRead>(
+    read_half: ReadHalf<S>,
+    pending: Pending,
+    mut writer_stopped: oneshot::Receiver<()>,
+) {
+    let mut frames = FramedRead::new(read_half, LengthDelimitedCodec::new());
+    loop {
+        let frame = tokio::select! {
+            frame = frames.next() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            // Either the connection is dropped, so no request is waiting, or it's
+            // broken and the waiting requests have failed.
+            _ = &mut writer_stopped => break,
+        };
+        let res = frame
+            .map_err(KvsError::from)
+            .and_then(|frame| Ok(serde_json::from_slice::<Frame<Response>>(&frame)?));
+        let frame = match res {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Error on receiving responses: {}", e);
+                break;
+            }
+        };
+        let tx = match &mut *pending.lock().unwrap() {
+            Some(pending) => pending.remove(&frame.id),
+            None => return,
+        };
+        // the request may have been cancelled
+        if let Some(tx) = tx {
+            let _ = tx.send(frame.body);
+        }
+    }
+    pending.lock().unwrap().take();
+}
+
+fn no_response() -> KvsError {
+    KvsError::StringError("No response received".to_owned())
+}
+
+/// Turns a response which doesn't match the request into an error.
+fn unexpected(resp: Response) -> KvsError {
+    match resp {
+        Response::Err(msg) => KvsError::StringError(msg),
+        _ => KvsError::StringError("Invalid response".to_owned()),
     }
 }
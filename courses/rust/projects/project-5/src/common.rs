@@ -1,16 +1,71 @@
+use crate::{KvPair, ServerStats, WriteBatch};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    WriteBatch(WriteBatch),
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    },
+    Backup {
+        dir: PathBuf,
+    },
+    /// Authenticates the connection, which must come before any other request if
+    /// the server requires a token.
+    Auth {
+        token: String,
+    },
+    Stats,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     Set,
     Remove,
+    WriteBatch,
+    CompareAndSwap,
+    /// The condition of a `CompareAndSwap` request is not met.
+    ConditionFailed,
+    Scan(Vec<KvPair>),
+    Backup,
+    Auth,
+    Stats(Box<ServerStats>),
     Err(String),
 }
+
+/// A request or a response tagged with the ID of the request.
+///
+/// A client may send many requests on one connection without waiting for their
+/// responses, which may come back in any order. The ID matches a response to its
+/// request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame<T> {
+    pub id: u64,
+    pub body: T,
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// A batch of writes which is applied atomically by `KvsEngine::write_batch`.
+///
+/// Operations are applied in the order they are added. Unlike `KvsEngine::remove`,
+/// removing a non-existent key in a batch is not an error.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"key1".to_vec(), b"value1".to_vec());
+/// batch.remove(b"key2".to_vec());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single operation in a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set {
+        /// The key
+        key: Vec<u8>,
+        /// The new value
+        value: Vec<u8>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds an operation setting the value of a key.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Adds an operation removing a key.
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the operations in the batch.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Consumes the batch and returns the operations.
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -1,43 +1,167 @@
 use std::cell::RefCell;
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
+use std::future::Future;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use async_trait::async_trait;
 use crossbeam::queue::ArrayQueue;
-use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use tokio::prelude::*;
-use tokio::sync::oneshot;
 
-use super::KvsEngine;
-use crate::thread_pool::ThreadPool;
+pub use self::cache::CacheStats;
+use self::cache::ValueCache;
+pub use self::compaction::CompactionStats;
+use self::compaction::{CompactionHandle, CompactionState};
+use self::hint::load_hint_file;
+use self::index::Index;
+pub use self::index::IndexMode;
+pub use self::inspect::{CorruptedRecord, GenerationStats, KvStoreInspector};
+use self::key_file::KeyFile;
+use self::record::read_binary_record;
+pub use self::record::{Compression, LogFormat};
+pub use self::snapshot::KvSnapshot;
+use self::snapshot::SnapshotRegistry;
+pub use self::sync::SyncPolicy;
+use self::sync::Syncer;
+use super::{now_millis, run_in_pool, BatchOp, EngineStats, KvPair, KvsEngine, WriteBatch};
+use crate::thread_pool::{InstrumentedThreadPool, ThreadPool};
 use crate::{KvsError, Result};
 
+mod cache;
+mod compaction;
+mod hint;
+mod index;
+mod inspect;
+mod key_file;
+mod record;
+mod snapshot;
+mod sync;
+
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const CACHE_CAPACITY: u64 = 8 * 1024 * 1024;
+
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{Compression, IndexMode, KvStoreOptions, LogFormat, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .log_format(LogFormat::Binary)
+///     .compression(Compression::Lz4)
+///     .compaction_threshold(4 * 1024 * 1024)
+///     .cache_capacity(64 * 1024 * 1024)
+///     .index_mode(IndexMode::Memory)
+///     .sync_policy(SyncPolicy::Always);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    log_format: LogFormat,
+    compression: Compression,
+    compaction_threshold: u64,
+    cache_capacity: u64,
+    index_mode: IndexMode,
+    sync_policy: SyncPolicy,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            log_format: LogFormat::default(),
+            compression: Compression::default(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            cache_capacity: CACHE_CAPACITY,
+            index_mode: IndexMode::default(),
+            sync_policy: SyncPolicy::default(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets the format of newly written log files.
+    ///
+    /// Existing log files are always read in the format they were written in.
+    /// Defaults to `LogFormat::Binary`.
+    pub fn log_format(mut self, log_format: LogFormat) -> KvStoreOptions {
+        self.log_format = log_format;
+        self
+    }
+
+    /// Sets how values are compressed in newly written records, including the
+    /// records copied by compactions.
+    ///
+    /// Existing records are always read with the compression they were written
+    /// with. JSON logs are never compressed. Defaults to `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets how many bytes of stale commands in the log trigger a compaction.
+    ///
+    /// Compactions run in a background thread. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> KvStoreOptions {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Sets how many bytes of recently read values are cached in memory.
+    ///
+    /// A value is cached if it fits in the whole cache, however small the cache is.
+    /// Zero disables the cache. Defaults to 8 MiB.
+    pub fn cache_capacity(mut self, cache_capacity: u64) -> KvStoreOptions {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Sets how keys are indexed.
+    ///
+    /// Defaults to `IndexMode::Memory`. See `IndexMode::Sparse` for stores with
+    /// more keys than fit in memory.
+    pub fn index_mode(mut self, index_mode: IndexMode) -> KvStoreOptions {
+        self.index_mode = index_mode;
+        self
+    }
+
+    /// Sets when writes are synced to the disk.
+    ///
+    /// Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+}
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores key/value pairs of bytes.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A skip list in memory stores the keys and the value locations for fast query.
 ///
+/// Stale commands in the log are cleared by compactions in a background thread, which
+/// copy the live entries to a new log file while writes continue in another one.
+///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
-/// # use tokio::prelude::*;
-/// # fn try_main() -> Result<()> {
+/// # async fn try_main() -> Result<()> {
 /// use std::env::current_dir;
 /// use kvs::KvsEngine;
 /// let mut store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
-/// store.set("key".to_owned(), "value".to_owned()).wait()?;
-/// let val = store.get("key".to_owned()).wait()?;
-/// assert_eq!(val, Some("value".to_owned()));
+/// store.set(b"key".to_vec(), b"value".to_vec()).await?;
+/// let val = store.get(b"key".to_vec()).await?;
+/// assert_eq!(val, Some(b"value".to_vec()));
 /// # Ok(())
 /// # }
 /// ```
@@ -46,10 +170,14 @@ pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data
     path: Arc<PathBuf>,
     // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
     writer: Arc<Mutex<KvStoreWriter>>,
-    thread_pool: P,
+    thread_pool: InstrumentedThreadPool<P>,
     reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    compaction: Arc<CompactionHandle>,
+    syncer: Arc<Syncer>,
+    snapshots: Arc<SnapshotRegistry>,
+    cache: Arc<ValueCache>,
 }
 
 impl<P: ThreadPool> KvStore<P> {
@@ -59,45 +187,123 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// `concurrency` specifies how many threads at most can read the database at the same time.
     ///
+    /// The index entries of compacted log files are loaded from their hint files if
+    /// possible. Other log files are replayed.
+    ///
+    /// A partially written record at the end of the newest log file, which is left
+    /// by a crash, is truncated. Other corrupted records are skipped and reported
+    /// in the log.
+    ///
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStore::open_with_options(path, concurrency, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// See `KvStore::open` for details.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
         let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let index = Arc::new(Index::new(options.index_mode));
 
-        let gen_list = sorted_gen_list(&path)?;
+        let mut gen_list = sorted_gen_list(&path)?;
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        if options.index_mode == IndexMode::Sparse {
+            if let Some(base) = open_newest_key_file(&path, &gen_list)? {
+                // The compaction file has all live entries of older log files
+                let base_gen = base.gen();
+                gen_list.retain(|&gen| gen > base_gen);
+                index.set_base(base);
+            }
+        }
         let mut uncompacted = 0;
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+        for (i, &gen) in gen_list.iter().enumerate() {
+            // A compaction file has a hint file to load the index from
+            if let Some(stale) = load_hint_file(&path, gen, &index)? {
+                uncompacted += stale;
+                continue;
+            }
+            let mut reader = LogReader::open(&path, gen)?;
+            let tail = i + 1 == gen_list.len();
+            let replay = load(gen, &mut reader, &index, tail)?;
+            uncompacted += replay.uncompacted;
+            if tail {
+                if let Some((offset, reason)) = replay.corrupted.first() {
+                    // The newest log file ends with a partially written record, possibly
+                    // because of a crash. Drop it so it won't be replayed as an older
+                    // log file next time.
+                    warn!(
+                        "Truncating {:?} at offset {}: {}",
+                        log_path(&path, gen),
+                        offset,
+                        reason
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(log_path(&path, gen))?
+                        .set_len(*offset)?;
+                }
+            } else {
+                for (offset, reason) in &replay.corrupted {
+                    error!(
+                        "Skipped corrupted record in {:?} at offset {}: {}",
+                        log_path(&path, gen),
+                        offset,
+                        reason
+                    );
+                }
+            }
             readers.insert(gen, reader);
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        let writer = new_log_file(&path, current_gen, options.log_format)?;
         let safe_point = Arc::new(AtomicU64::new(0));
+        let cache = Arc::new(ValueCache::new(options.cache_capacity));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
             safe_point,
-            readers: RefCell::new(BTreeMap::new()),
+            // reuse the handles opened to replay the logs
+            readers: RefCell::new(readers),
+            cache: Arc::clone(&cache),
         };
 
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
+        let syncer = Arc::new(Syncer::new(options.sync_policy, writer.get_ref())?);
+        let snapshots = Arc::new(SnapshotRegistry::new(Arc::clone(&path)));
+        let (compaction_state, compaction_tasks) = CompactionState::new();
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
             writer,
+            format: options.log_format,
+            compression: options.compression,
             current_gen,
             uncompacted,
-            path: Arc::clone(&path),
+            compaction_threshold: options.compaction_threshold,
+            compaction: Arc::clone(&compaction_state),
+            syncer: Arc::clone(&syncer),
             index: Arc::clone(&index),
-        };
+            cache: Arc::clone(&cache),
+        }));
+        let compaction = CompactionHandle::spawn(
+            compaction_state,
+            compaction_tasks,
+            Arc::clone(&path),
+            Arc::clone(&index),
+            Arc::clone(&writer),
+            reader.clone(),
+            Arc::clone(&snapshots),
+        )?;
 
-        let thread_pool = P::new(concurrency)?;
+        let thread_pool = InstrumentedThreadPool::new(concurrency)?;
         let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
         for _ in 1..concurrency {
             reader_pool.push(reader.clone()).unwrap();
@@ -107,90 +313,298 @@ impl<P: ThreadPool> KvStore<P> {
         Ok(KvStore {
             path,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
             thread_pool,
             reader_pool,
+            compaction: Arc::new(compaction),
+            syncer,
+            snapshots,
+            cache,
         })
     }
+
+    /// Triggers a compaction in the background.
+    ///
+    /// The returned future resolves when the compaction finishes. If a compaction is
+    /// already running, the new one starts after it.
+    pub fn compact(&self) -> impl Future<Output = Result<()>> {
+        let done = self.compaction.compact();
+        async move {
+            done.await
+                .map_err(|e| KvsError::StringError(format!("{}", e)))?
+        }
+    }
+
+    /// Takes a snapshot of the store.
+    ///
+    /// The index in memory is copied, which takes time and memory in proportion to
+    /// the keys in memory. The copy is made after the writer is unlocked, so writes
+    /// don't wait for it, but while it's made they record the old positions of the
+    /// keys they change. Values are read from the log when they are read from the
+    /// snapshot.
+    pub fn snapshot(&self) -> KvSnapshot {
+        let (id, copy, format, compression, taken_at) = {
+            let writer = self.writer.lock().unwrap();
+            (
+                self.snapshots.register(),
+                self.index.start_copy(),
+                writer.format,
+                writer.compression,
+                now_millis(),
+            )
+        };
+        KvSnapshot::new(
+            Arc::clone(&self.snapshots),
+            id,
+            self.index.finish_copy(copy),
+            format,
+            compression,
+            taken_at,
+        )
+    }
+
+    /// Returns the status of the background compaction.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        let uncompacted = self.writer.lock().unwrap().uncompacted;
+        self.compaction.stats(uncompacted)
+    }
+
+    /// Returns the status of the value cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 }
 
+#[async_trait]
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_expiring(key, value, None).await
+    }
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// The expiration time is persisted in the log. Expired keys are dropped in the
+    /// next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.set_expiring(key, value, Some(expires_at)).await
     }
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a given key.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    /// Returns `None` if the given key does not exist or has expired.
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        run_in_pool(&self.thread_pool, move || match index.get(&key)? {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => {
+                let reader = reader_pool.pop().unwrap();
+                let res = reader.read_value(cmd_pos);
+                reader_pool.push(reader).unwrap();
+                Ok(Some(res?))
+            }
+            _ => Ok(None),
+        })
+        .await
+    }
+
+    async fn get_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
         let reader_pool = self.reader_pool.clone();
         let index = self.index.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
+        run_in_pool(&self.thread_pool, move || {
+            let now = now_millis();
+            match index.get(&key)? {
+                Some(cmd_pos) if !cmd_pos.is_expired(now) => {
                     let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
+                    let res = reader.read_value(cmd_pos);
                     reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
+                    let ttl = cmd_pos
+                        .expires_at
+                        .map(|expires_at| Duration::from_millis(expires_at - now));
+                    Ok(Some((res?, ttl)))
                 }
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+                _ => Ok(None),
             }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        })
+        .await
     }
 
     /// Removes a given key.
     ///
     /// # Error
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has expired.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    async fn remove(&self, key: Vec<u8>) -> Result<()> {
         let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        let seq = run_in_pool(&self.thread_pool, move || {
+            writer.lock().unwrap().remove(key)
+        })
+        .await?;
+        self.syncer.wait(seq).await
+    }
+
+    /// Applies all operations in the batch atomically.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let writer = self.writer.clone();
+        let seq = run_in_pool(&self.thread_pool, move || {
+            writer.lock().unwrap().write_batch(batch)
+        })
+        .await?;
+        self.syncer.wait(seq).await
+    }
+
+    /// Sets or removes a key if its current value is `expected`.
+    ///
+    /// The current value is read with the writer locked, so no other write can
+    /// happen between the comparison and the swap.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` if the current value is not `expected`.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let writer = self.writer.clone();
+        let reader_pool = self.reader_pool.clone();
+        let seq = run_in_pool(&self.thread_pool, move || {
+            let reader = reader_pool.pop().unwrap();
+            let res = writer
+                .lock()
+                .unwrap()
+                .compare_and_swap(&reader, key, expected, new);
+            reader_pool.push(reader).unwrap();
+            res
+        })
+        .await?;
+        self.syncer.wait(seq).await
+    }
+
+    async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KvPair>> {
+        self.scan_index(move |index| live_range(index, start, end, limit))
+            .await
+    }
+
+    async fn scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let index = self.index.clone();
+        run_in_pool(&self.thread_pool, move || {
+            let entries = live_range(&index, start, end, limit)?;
+            Ok(entries.into_iter().map(|(key, _)| key).collect())
+        })
+        .await
+    }
+
+    async fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        self.scan_index(move |index| {
+            let now = now_millis();
+            index
+                .range(Bound::Included(prefix.clone()), Bound::Unbounded)
+                .take_while(|entry| match entry {
+                    Ok((key, _)) => key.starts_with(&prefix),
+                    Err(_) => true,
+                })
+                .filter(|entry| match entry {
+                    Ok((_, cmd_pos)) => !cmd_pos.is_expired(now),
+                    Err(_) => true,
+                })
+                .take(limit.unwrap_or_else(usize::max_value))
+                .collect()
+        })
+        .await
+    }
+
+    /// Writes the keys in a snapshot of the store to a single log file in `dir`.
+    ///
+    /// See `KvSnapshot::backup` for details.
+    async fn backup(&self, dir: PathBuf) -> Result<()> {
+        let store = self.clone();
+        run_in_pool(&self.thread_pool, move || store.snapshot().backup(dir)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let writer = Arc::clone(&self.writer);
+        run_in_pool(&self.thread_pool, move || writer.lock().unwrap().sync()).await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        let path = Arc::clone(&self.path);
+        let disk_usage = run_in_pool(&self.thread_pool, move || dir_size(&path)).await?;
+        Ok(EngineStats {
+            disk_usage,
+            thread_pool: self.thread_pool.stats(),
+            compaction: Some(self.compaction_stats()),
+            cache: Some(self.cache_stats()),
+        })
+    }
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// Sets the value of a key, which expires at `expires_at` in milliseconds since
+    /// the Unix epoch if specified.
+    async fn set_expiring(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let writer = self.writer.clone();
+        let seq = run_in_pool(&self.thread_pool, move || {
+            writer.lock().unwrap().set(key, value, expires_at)
+        })
+        .await?;
+        self.syncer.wait(seq).await
+    }
+
+    /// Collects entries from the index with `f` and reads their values.
+    async fn scan_index<F>(&self, f: F) -> Result<Vec<KvPair>>
+    where
+        F: FnOnce(&Index) -> Result<Vec<(Vec<u8>, CommandPos)>> + Send + 'static,
+    {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        run_in_pool(&self.thread_pool, move || {
+            f(&index).and_then(|entries| {
+                let reader = reader_pool.pop().unwrap();
+                let res = entries
+                    .into_iter()
+                    .map(|(key, cmd_pos)| Ok((key, reader.read_value(cmd_pos)?)))
+                    .collect();
+                reader_pool.push(reader).unwrap();
+                res
+            })
+        })
+        .await
     }
 }
 
@@ -204,7 +618,9 @@ struct KvStoreReader {
     path: Arc<PathBuf>,
     // generation of the latest compaction file
     safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    readers: RefCell<BTreeMap<u64, LogReader>>,
+    // shared by all clones
+    cache: Arc<ValueCache>,
 }
 
 impl KvStoreReader {
@@ -226,30 +642,70 @@ impl KvStoreReader {
     }
 
     /// Read the log file at the given `CommandPos`.
+    ///
+    /// The closure also receives the format of the log file.
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(LogFormat, io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
     {
         self.close_stale_handles();
 
         let mut readers = self.readers.borrow_mut();
         // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+        if let Entry::Vacant(entry) = readers.entry(cmd_pos.gen) {
+            entry.insert(LogReader::open(&self.path, cmd_pos.gen)?);
         }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let LogReader { format, reader } = readers.get_mut(&cmd_pos.gen).unwrap();
         reader.seek(SeekFrom::Start(cmd_pos.pos))?;
         let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        f(*format, cmd_reader)
+    }
+
+    /// Copy the command at the given `CommandPos` to a log file of the given format.
+    ///
+    /// Values are compressed with `compression` unless it's `Compression::None`, in
+    /// which case records of the same format are copied as they are.
+    fn copy_command(
+        &self,
+        cmd_pos: CommandPos,
+        format: LogFormat,
+        compression: Compression,
+        writer: &mut BufWriterWithPos<File>,
+    ) -> Result<()> {
+        self.read_and(cmd_pos, |entry_format, mut entry_reader| {
+            if entry_format == format && compression == Compression::None {
+                io::copy(&mut entry_reader, writer)?;
+            } else {
+                // the entry is in an old log file of another format or may need to be
+                // compressed
+                let cmd = entry_format.decode(entry_reader)?;
+                format.encode(&format.compress(cmd, compression)?, writer)?;
+            }
+            Ok(())
+        })
     }
 
     // Read the log file at the given `CommandPos` and deserialize it to `Command`.
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+        self.read_and(cmd_pos, |format, cmd_reader| format.decode(cmd_reader))
+    }
+
+    // Read the value of the "set" command at the given `CommandPos`, through the
+    // value cache.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        if let Some(value) = self.cache.get(cmd_pos) {
+            return Ok(value);
+        }
+        if let Command::Set {
+            value, compression, ..
+        } = self.read_command(cmd_pos)?
+        {
+            let value = compression.decompress(value)?;
+            self.cache.insert(cmd_pos, value.clone());
+            Ok(value)
+        } else {
+            Err(KvsError::UnexpectedCommandType)
+        }
     }
 }
 
@@ -260,131 +716,161 @@ impl Clone for KvStoreReader {
             safe_point: Arc::clone(&self.safe_point),
             // don't use other KvStoreReader's readers
             readers: RefCell::new(BTreeMap::new()),
+            cache: Arc::clone(&self.cache),
         }
     }
 }
 
 struct KvStoreWriter {
-    reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
+    // format of newly written log files
+    format: LogFormat,
+    compression: Compression,
     current_gen: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    compaction_threshold: u64,
+    compaction: Arc<CompactionState>,
+    syncer: Arc<Syncer>,
+    index: Arc<Index>,
+    cache: Arc<ValueCache>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        self.write(Command::set(key, value, expires_at))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
-            }
-
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
+    fn remove(&mut self, key: Vec<u8>) -> Result<u64> {
+        let now = now_millis();
+        let live = match self.index.get(&key)? {
+            Some(cmd_pos) => !cmd_pos.is_expired(now),
+            None => false,
+        };
+        if live {
+            self.write(Command::remove(key))
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
-
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+    /// Writes `new` if the current value of the key, read with `reader`, is `expected`.
+    fn compare_and_swap(
+        &mut self,
+        reader: &KvStoreReader,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<u64> {
+        let current = match self.index.get(&key)? {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => Some(reader.read_value(cmd_pos)?),
+            _ => None,
+        };
+        if current != expected {
+            return Err(KvsError::ConditionFailed);
         }
-        compaction_writer.flush()?;
-
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
-
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
-
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
-            }
+        match new {
+            Some(value) => self.set(key, value, None),
+            None if current.is_some() => self.write(Command::remove(key)),
+            // nothing to write
+            None => Ok(0),
+        }
+    }
+
+    /// Writes all commands in the batch as a single record, so a partially written
+    /// batch is dropped as a whole on the next replay.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(0);
         }
-        self.uncompacted = 0;
+        self.write(Command::Batch(
+            batch.into_ops().into_iter().map(Command::from).collect(),
+        ))
+    }
 
+    /// Syncs the current log to the disk.
+    ///
+    /// Older logs are synced by `Syncer::switch_file` when the writer switches to a
+    /// new one, whatever the sync policy is.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
         Ok(())
     }
+
+    /// Writes the command to the current log and applies it to the index.
+    ///
+    /// Returns the sequence number of the write to wait for with `Syncer::wait`.
+    /// A compaction is requested if there are too many stale commands.
+    fn write(&mut self, cmd: Command) -> Result<u64> {
+        let pos = self.writer.pos;
+        let cmd = self.format.compress(cmd, self.compression)?;
+        self.format.encode(&cmd, &mut self.writer)?;
+        self.writer.flush()?;
+        let seq = self.syncer.written(self.writer.get_ref())?;
+
+        let range = pos..self.writer.pos;
+        self.uncompacted += apply_command(
+            &self.index,
+            Some(&self.cache),
+            self.format,
+            self.current_gen,
+            cmd,
+            range,
+        )?;
+        if self.uncompacted > self.compaction_threshold {
+            self.compaction.request();
+        }
+        Ok(seq)
+    }
+}
+
+/// Returns at most `limit` entries of the index in the range `[start, end)` which
+/// are not expired.
+fn live_range(
+    index: &Index,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    limit: Option<usize>,
+) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+    let now = now_millis();
+    index
+        .range(Bound::Included(start), end)
+        .filter(|entry| match entry {
+            Ok((_, cmd_pos)) => !cmd_pos.is_expired(now),
+            Err(_) => true,
+        })
+        .take(limit.unwrap_or_else(usize::max_value))
+        .collect()
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
+fn new_log_file(path: &Path, gen: u64, format: LogFormat) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let mut writer =
+        BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    format.write_file_header(&mut writer)?;
+    writer.flush()?;
     Ok(writer)
 }
 
+/// Opens the key file of the newest compaction file in the given generations.
+fn open_newest_key_file(path: &Path, gen_list: &[u64]) -> Result<Option<KeyFile>> {
+    for &gen in gen_list.iter().rev() {
+        if let Some(key_file) = KeyFile::open(path, gen)? {
+            return Ok(Some(key_file));
+        }
+    }
+    Ok(None)
+}
+
 /// Returns sorted generation numbers in the given directory
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
@@ -399,39 +885,141 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
+/// Returns the total size of the files in the given directory
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// The result of replaying a log file.
+struct Replay {
+    // number of bytes that can be saved after a compaction
+    uncompacted: u64,
+    // offsets of the corrupted records and the reasons
+    corrupted: Vec<(u64, String)>,
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+/// Corrupted records are skipped if possible. A corrupted JSON record or a truncated
+/// binary record stops the replay of the file because the following records cannot
+/// be located. If `tail` is true, i.e. the log file is the newest one, the replay
+/// stops at the first corrupted record because it is most likely a torn write.
+fn load(gen: u64, reader: &mut LogReader, index: &Index, tail: bool) -> Result<Replay> {
+    let mut uncompacted = 0;
+    let mut corrupted = Vec::new();
+
+    // To make sure we read from the first record of the file
+    let reader = &mut reader.reader;
+    let format = LogFormat::detect(reader)?;
+    let mut pos = reader.pos;
+    match format {
+        LogFormat::Json => {
+            // The deserializer counts bytes from where it starts
+            let start = pos;
+            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let new_pos = start + stream.byte_offset() as u64;
+                match cmd {
+                    Ok(cmd) => {
+                        uncompacted += apply_command(index, None, format, gen, cmd, pos..new_pos)?
+                    }
+                    Err(e) if e.is_io() => return Err(e.into()),
+                    Err(e) => {
+                        corrupted.push((pos, format!("{}", e)));
+                        break;
+                    }
                 }
-                index.insert(key, (gen, pos..new_pos).into());
+                pos = new_pos;
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+        }
+        LogFormat::Binary => loop {
+            match read_binary_record(reader) {
+                Ok(Some(cmd)) => {
+                    uncompacted += apply_command(index, None, format, gen, cmd, pos..reader.pos)?
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                Ok(None) => break,
+                Err(KvsError::CorruptedLog(reason)) => {
+                    corrupted.push((pos, reason));
+                    if tail {
+                        break;
+                    }
+                }
+                Err(e) => return Err(e),
             }
+            pos = reader.pos;
+        },
+    }
+    Ok(Replay {
+        uncompacted,
+        corrupted,
+    })
+}
+
+/// Applies a command at the given range of a log file to the index.
+///
+/// The cached values of overwritten or removed commands are invalidated.
+///
+/// Returns how many bytes become stale, i.e. can be saved after a compaction.
+fn apply_command(
+    index: &Index,
+    cache: Option<&ValueCache>,
+    format: LogFormat,
+    gen: u64,
+    cmd: Command,
+    range: Range<u64>,
+) -> Result<u64> {
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                expires_at,
+                ..(gen, range).into()
+            };
+            let old_pos = index.insert(key, cmd_pos);
+            Ok(invalidate(cache, old_pos))
+        }
+        Command::Remove { key } => {
+            let old_pos = index.remove(&key, gen);
+            let stale = invalidate(cache, old_pos);
+            // the "remove" command itself can be deleted in the next compaction
+            // so we add its length
+            Ok(stale + range.end - range.start)
+        }
+        Command::Batch(cmds) => {
+            // Index the commands in the batch separately. Only the bytes between them,
+            // i.e. the framing of the batch, are stale from the beginning.
+            let ranges = format.batch_ranges(&cmds)?;
+            let mut stale = range.end - range.start;
+            for (cmd, cmd_range) in cmds.into_iter().zip(ranges) {
+                stale -= cmd_range.end - cmd_range.start;
+                let cmd_range = range.start + cmd_range.start..range.start + cmd_range.end;
+                stale += apply_command(index, cache, format, gen, cmd, cmd_range)?;
+            }
+            Ok(stale)
+        }
+    }
+}
+
+/// Invalidates the cached value of a replaced command.
+///
+/// Returns the length of the command, which becomes stale.
+fn invalidate(cache: Option<&ValueCache>, old_pos: Option<CommandPos>) -> u64 {
+    match (cache, old_pos) {
+        (Some(cache), Some(old_pos)) => {
+            cache.invalidate(old_pos);
+            old_pos.len
         }
-        pos = new_pos;
+        (None, Some(old_pos)) => old_pos.len,
+        (_, None) => 0,
     }
-    Ok(uncompacted)
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
@@ -441,26 +1029,67 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "record::json_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "record::json_bytes")]
+        value: Vec<u8>,
+        // milliseconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        // how the value is compressed, which is always none in JSON records
+        #[serde(skip)]
+        compression: Compression,
+    },
+    Remove {
+        #[serde(with = "record::json_bytes")]
+        key: Vec<u8>,
+    },
+    Batch(Vec<Command>),
+}
+
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Command {
+        match op {
+            BatchOp::Set { key, value } => Command::set(key, value, None),
+            BatchOp::Remove { key } => Command::Remove { key },
+        }
+    }
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            compression: Compression::None,
+        }
     }
 
-    fn remove(key: String) -> Command {
+    fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// Represents the position and length of a serialized command in the log,
+/// along with the expiration time of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -469,10 +1098,25 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
         }
     }
 }
 
+/// A reader of a log file, along with the format of the file.
+struct LogReader {
+    format: LogFormat,
+    reader: BufReaderWithPos<File>,
+}
+
+impl LogReader {
+    fn open(path: &Path, gen: u64) -> Result<LogReader> {
+        let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+        let format = LogFormat::detect(&mut reader)?;
+        Ok(LogReader { format, reader })
+    }
+}
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -480,7 +1124,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -510,12 +1154,16 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
         })
     }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use super::CommandPos;
+
+/// The number of independently locked parts of the cache, so readers of
+/// different values rarely wait for each other.
+const SHARDS: usize = 16;
+
+/// The status of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// The number of reads served from the cache.
+    pub hits: u64,
+    /// The number of reads which had to read the log.
+    pub misses: u64,
+    /// The number of bytes of the cached values.
+    pub size: u64,
+    /// The maximum number of bytes of the cached values.
+    pub capacity: u64,
+}
+
+/// A bounded LRU cache of recently read values shared by the readers of a
+/// `KvStore`.
+///
+/// Values are cached by their positions in the log rather than by their keys. A
+/// position is never reused for another value, so a reader racing with a write
+/// can't put an outdated value in the cache for the key. The entries of
+/// overwritten, removed or compacted commands are invalidated to free the space.
+///
+/// The capacity bounds the values in all shards together, so a value may take up
+/// to the whole capacity. Each shard evicts its own least recently used values
+/// first, and the other shards are evicted from if it has no other values.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    capacity: u64,
+    // the number of bytes of the values in all shards
+    size: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The values in a part of the cache by the positions of their commands.
+type Shard = LruCache<(u64, u64), Vec<u8>>;
+
+impl ValueCache {
+    /// Creates a cache of at most `capacity` bytes of values. A zero capacity
+    /// disables the cache.
+    pub(super) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(LruCache::unbounded()))
+                .collect(),
+            capacity,
+            size: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of the command at the given position.
+    pub(super) fn get(&self, cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let value = self
+            .shard(cmd_pos)
+            .get(&(cmd_pos.gen, cmd_pos.pos))
+            .cloned();
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    /// Caches the value of the command at the given position, evicting the least
+    /// recently used values if the cache is full.
+    ///
+    /// Values larger than the cache are not cached.
+    pub(super) fn insert(&self, cmd_pos: CommandPos, value: Vec<u8>) {
+        let len = value.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        let home = shard_index(cmd_pos);
+        {
+            let mut shard = self.shards[home].lock().unwrap();
+            if let Some(old) = shard.put((cmd_pos.gen, cmd_pos.pos), value) {
+                self.size.fetch_sub(old.len() as u64, Ordering::Relaxed);
+            }
+            self.size.fetch_add(len, Ordering::Relaxed);
+            while self.size.load(Ordering::Relaxed) > self.capacity && shard.len() > 1 {
+                self.evict(&mut shard);
+            }
+        }
+        // Only one shard is locked at a time, so inserts into different shards
+        // don't wait for each other.
+        for i in (1..SHARDS).map(|i| (home + i) % SHARDS) {
+            if self.size.load(Ordering::Relaxed) <= self.capacity {
+                break;
+            }
+            let mut shard = self.shards[i].lock().unwrap();
+            while self.size.load(Ordering::Relaxed) > self.capacity && self.evict(&mut shard) {}
+        }
+    }
+
+    /// Drops the cached value of the command at the given position.
+    pub(super) fn invalidate(&self, cmd_pos: CommandPos) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(old) = self.shard(cmd_pos).pop(&(cmd_pos.gen, cmd_pos.pos)) {
+            self.size.fetch_sub(old.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.size.load(Ordering::Relaxed),
+            capacity: self.capacity,
+        }
+    }
+
+    fn shard(&self, cmd_pos: CommandPos) -> MutexGuard<'_, Shard> {
+        self.shards[shard_index(cmd_pos)].lock().unwrap()
+    }
+
+    /// Evicts the least recently used value of the shard.
+    ///
+    /// Returns whether the shard had a value to evict.
+    fn evict(&self, shard: &mut Shard) -> bool {
+        match shard.pop_lru() {
+            Some((_, evicted)) => {
+                self.size.fetch_sub(evicted.len() as u64, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn shard_index(cmd_pos: CommandPos) -> usize {
+    (cmd_pos.gen ^ cmd_pos.pos) as usize % SHARDS
+}
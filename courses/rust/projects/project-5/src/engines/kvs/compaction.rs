@@ -0,0 +1,302 @@
+use std::io::Write;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use super::hint::HintWriter;
+use super::index::{Index, IndexMode};
+use super::key_file::KeyFile;
+use super::snapshot::SnapshotRegistry;
+use super::{new_log_file, now_millis, sorted_gen_list, CommandPos, KvStoreReader, KvStoreWriter};
+use crate::metrics::{AtomicHistogram, Histogram};
+use crate::{KvsError, Result};
+
+/// The number of copied entries that are moved in the index at a time.
+///
+/// The writer is locked while the index is updated, so writes wait for at most
+/// one chunk instead of the whole compaction.
+const INDEX_UPDATE_CHUNK: usize = 1024;
+
+/// The status of the background compaction of a `KvStore`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// Whether a compaction is running.
+    pub running: bool,
+    /// The number of compactions finished since the store was opened.
+    pub finished: u64,
+    /// The number of compactions failed since the store was opened.
+    pub failed: u64,
+    /// How long the finished compactions took.
+    pub durations: Histogram,
+    /// The number of bytes of stale commands that a compaction could save.
+    pub uncompacted: u64,
+}
+
+pub(super) enum Task {
+    /// Runs a compaction. The result is sent back if the compaction is triggered
+    /// by the user.
+    Compact(Option<oneshot::Sender<Result<()>>>),
+    Shutdown,
+}
+
+/// The state shared by the `KvStore`, the writer and the compaction thread.
+pub(super) struct CompactionState {
+    tasks: Sender<Task>,
+    // whether a compaction is requested by the writer and not finished yet
+    pending: AtomicBool,
+    running: AtomicBool,
+    finished: AtomicU64,
+    failed: AtomicU64,
+    durations: AtomicHistogram,
+}
+
+impl CompactionState {
+    pub(super) fn new() -> (Arc<CompactionState>, Receiver<Task>) {
+        let (tasks, rx) = channel::unbounded();
+        let state = CompactionState {
+            tasks,
+            pending: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            finished: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            durations: AtomicHistogram::new(),
+        };
+        (Arc::new(state), rx)
+    }
+
+    /// Requests a compaction because there are too many stale commands.
+    ///
+    /// It does nothing if a previous request is not finished yet.
+    pub(super) fn request(&self) {
+        if !self.pending.swap(true, Ordering::SeqCst) {
+            self.send(Task::Compact(None));
+        }
+    }
+
+    fn send(&self, task: Task) {
+        if self.tasks.send(task).is_err() {
+            error!("Compaction thread is stopped");
+        }
+    }
+}
+
+/// Owns the compaction thread.
+///
+/// The thread is stopped after the running compaction finishes when the handle
+/// is dropped, i.e. when all clones of the `KvStore` are dropped.
+pub(super) struct CompactionHandle {
+    state: Arc<CompactionState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CompactionHandle {
+    pub(super) fn spawn(
+        state: Arc<CompactionState>,
+        tasks: Receiver<Task>,
+        path: Arc<PathBuf>,
+        index: Arc<Index>,
+        writer: Arc<Mutex<KvStoreWriter>>,
+        reader: KvStoreReader,
+        snapshots: Arc<SnapshotRegistry>,
+    ) -> Result<CompactionHandle> {
+        let compactor = Compactor {
+            state: Arc::clone(&state),
+            path,
+            index,
+            writer,
+            reader,
+            snapshots,
+        };
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || compactor.run(tasks))?;
+        Ok(CompactionHandle {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues a compaction and returns the receiver of its result.
+    pub(super) fn compact(&self) -> oneshot::Receiver<Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        self.state.send(Task::Compact(Some(tx)));
+        rx
+    }
+
+    pub(super) fn stats(&self, uncompacted: u64) -> CompactionStats {
+        CompactionStats {
+            running: self.state.running.load(Ordering::SeqCst),
+            finished: self.state.finished.load(Ordering::SeqCst),
+            failed: self.state.failed.load(Ordering::SeqCst),
+            durations: self.state.durations.snapshot(),
+            uncompacted,
+        }
+    }
+}
+
+impl Drop for CompactionHandle {
+    fn drop(&mut self) {
+        self.state.send(Task::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// Runs compactions in the compaction thread.
+struct Compactor {
+    state: Arc<CompactionState>,
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    reader: KvStoreReader,
+    snapshots: Arc<SnapshotRegistry>,
+}
+
+impl Compactor {
+    fn run(self, tasks: Receiver<Task>) {
+        for task in tasks {
+            let done = match task {
+                Task::Compact(done) => done,
+                Task::Shutdown => break,
+            };
+            self.state.running.store(true, Ordering::SeqCst);
+            let started = Instant::now();
+            let res = self.compact();
+            self.state.running.store(false, Ordering::SeqCst);
+            self.state.pending.store(false, Ordering::SeqCst);
+            if res.is_ok() {
+                self.state.durations.record(started.elapsed());
+                self.state.finished.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.state.failed.fetch_add(1, Ordering::SeqCst);
+            }
+            match done {
+                Some(done) => {
+                    if done.send(res).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+                None => {
+                    if let Err(e) = res {
+                        error!("Compaction failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Clears stale entries and expired keys in the log.
+    ///
+    /// The writer is switched to a new log file first, so writes continue while the
+    /// live entries are copied to the compaction file. A hint file is written along
+    /// with the compaction file for faster loading.
+    ///
+    /// In `IndexMode::Sparse`, the index switches to the hint file as the key file
+    /// at the end instead of pointing the keys to the copied entries.
+    fn compact(&self) -> Result<()> {
+        // current gen + 1 is for the compaction file and current gen + 2 for new writes
+        let (compaction_gen, format, compression) = {
+            let mut writer = self.writer.lock().unwrap();
+            let compaction_gen = writer.current_gen + 1;
+            let new_writer = new_log_file(&self.path, compaction_gen + 1, writer.format)?;
+            writer
+                .syncer
+                .switch_file(writer.writer.get_ref(), new_writer.get_ref())?;
+            writer.writer = new_writer;
+            writer.current_gen = compaction_gen + 1;
+            writer.uncompacted = 0;
+            (compaction_gen, writer.format, writer.compression)
+        };
+
+        let mut compaction_writer = new_log_file(&self.path, compaction_gen, format)?;
+        let mut hint_writer = HintWriter::create(&self.path, compaction_gen)?;
+        let mut copied = Vec::with_capacity(INDEX_UPDATE_CHUNK);
+        let now = now_millis();
+        let sparse = self.index.mode() == IndexMode::Sparse;
+        for entry in self.index.range(Bound::Unbounded, Bound::Unbounded) {
+            let (key, old_pos) = entry?;
+            if old_pos.gen >= compaction_gen {
+                // written after the compaction started
+                continue;
+            }
+            if old_pos.is_expired(now) {
+                if !sparse {
+                    copied.push((key, old_pos, None));
+                }
+                continue;
+            }
+            let new_pos = compaction_writer.pos; // pos in the new log file
+            self.reader
+                .copy_command(old_pos, format, compression, &mut compaction_writer)?;
+            let new_pos = CommandPos {
+                expires_at: old_pos.expires_at,
+                ..(compaction_gen, new_pos..compaction_writer.pos).into()
+            };
+            hint_writer.add(&key, new_pos)?;
+            if sparse {
+                continue;
+            }
+            copied.push((key, old_pos, Some(new_pos)));
+            if copied.len() >= INDEX_UPDATE_CHUNK {
+                // readers must be able to see the copied entries before the index is updated
+                compaction_writer.flush()?;
+                self.update_index(&mut copied);
+            }
+        }
+        compaction_writer.flush()?;
+        self.update_index(&mut copied);
+        hint_writer.finish(compaction_writer.pos)?;
+        // the stale log files are deleted below, whatever the sync policy is
+        compaction_writer.get_ref().sync_all()?;
+        if sparse {
+            let key_file = KeyFile::open(&self.path, compaction_gen)?.ok_or_else(|| {
+                KvsError::StringError("The hint file of the compaction is invalid".to_owned())
+            })?;
+            let mut writer = self.writer.lock().unwrap();
+            let (stale, dropped) = self.index.switch_base(key_file)?;
+            writer.uncompacted += stale;
+            for old_pos in dropped {
+                self.reader.cache.invalidate(old_pos);
+            }
+        }
+
+        self.reader
+            .safe_point
+            .store(compaction_gen, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // remove stale log files, unless snapshots may still read them
+        // When `KvStoreReader` is used next time, it will clear its stale file handles.
+        let stale_gens = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        self.snapshots.remove_stale_files(stale_gens);
+
+        Ok(())
+    }
+
+    /// Points the index to the copied entries and removes the dropped expired ones,
+    /// unless they have been overwritten or removed since they were copied.
+    fn update_index(&self, copied: &mut Vec<(Vec<u8>, CommandPos, Option<CommandPos>)>) {
+        // All other changes to the index are made with the writer locked
+        let mut writer = self.writer.lock().unwrap();
+        for (key, old_pos, new_pos) in copied.drain(..) {
+            if self.index.move_entry(key, old_pos, new_pos) {
+                self.reader.cache.invalidate(old_pos);
+            } else if let Some(new_pos) = new_pos {
+                writer.uncompacted += new_pos.len;
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crc32fast::Hasher;
+
+use super::index::Index;
+use super::{log_path, CommandPos};
+use crate::Result;
+
+/// Bytes at the beginning of every hint file.
+const MAGIC: &[u8; 8] = b"KVSHINT\x02";
+
+/// Size of the fixed part of an entry: key length, position, length and expiration
+/// time.
+const ENTRY_HEADER_LEN: usize = 4 + 8 + 8 + 8;
+
+/// Size of the trailer: length of the log file and checksum.
+const TRAILER_LEN: usize = 8 + 4;
+
+/// Writes the hint file of a compaction file.
+///
+/// A hint file lists the location of every command in its log file, so the index
+/// can be loaded without reading the values:
+///
+/// ```text
+/// | magic (8) | entry ... | log len (8) | crc32 (4) |
+/// entry: | key len (4) | pos (8) | len (8) | expires at (8) | key |
+/// ```
+///
+/// All integers are little-endian. The checksum covers everything before itself.
+/// The expiration time is 0 for keys without a TTL.
+/// The log length guards against a log file that is changed after the hint file is
+/// written.
+///
+/// The file is written to a temporary path and renamed when finished, so a hint
+/// file is either complete or missing after a crash.
+///
+/// The entries are added in ascending order of the keys, so a hint file can also
+/// serve as the sorted key file of `IndexMode::Sparse`.
+pub(super) struct HintWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    hasher: Hasher,
+}
+
+impl HintWriter {
+    pub(super) fn create(dir: &Path, gen: u64) -> Result<HintWriter> {
+        let path = hint_path(dir, gen);
+        let tmp_path = path.with_extension("hint.tmp");
+        let mut writer = HintWriter {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            path,
+            tmp_path,
+            hasher: Hasher::new(),
+        };
+        writer.write(MAGIC)?;
+        Ok(writer)
+    }
+
+    /// Adds the location of a "set" command in the log file.
+    pub(super) fn add(&mut self, key: &[u8], cmd_pos: CommandPos) -> Result<()> {
+        self.write(&(key.len() as u32).to_le_bytes())?;
+        self.write(&cmd_pos.pos.to_le_bytes())?;
+        self.write(&cmd_pos.len.to_le_bytes())?;
+        self.write(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes())?;
+        self.write(key)
+    }
+
+    /// Finishes the hint file of a log file with the given length.
+    pub(super) fn finish(mut self, log_len: u64) -> Result<()> {
+        self.write(&log_len.to_le_bytes())?;
+        let crc = self.hasher.finalize();
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.writer.write_all(buf)?;
+        Ok(())
+    }
+}
+
+/// Loads the index entries of a log file from its hint file.
+///
+/// Returns how many bytes become stale like `load` does, or `None` if the hint file
+/// is missing or invalid, in which case the log file has to be replayed.
+pub(super) fn load_hint_file(dir: &Path, gen: u64, index: &Index) -> Result<Option<u64>> {
+    let path = hint_path(dir, gen);
+    let mut data = Vec::new();
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+    let entries = match parse(&data, log_len) {
+        Ok(entries) => entries,
+        Err(reason) => {
+            warn!("Ignoring {:?}: {}", path, reason);
+            return Ok(None);
+        }
+    };
+
+    let mut uncompacted = 0;
+    for (key, cmd_pos) in entries {
+        let old_pos = index.insert(key, CommandPos { gen, ..cmd_pos });
+        uncompacted += old_pos.map_or(0, |old_pos| old_pos.len);
+    }
+    Ok(Some(uncompacted))
+}
+
+/// Reads a hint file without loading it into memory, calling `f` with the offset
+/// and the key of every entry.
+///
+/// Returns the offset where the entries end, or `None` if the hint file is missing
+/// or invalid.
+pub(super) fn scan_hint_file<F>(dir: &Path, gen: u64, f: F) -> Result<Option<u64>>
+where
+    F: FnMut(u64, &[u8]),
+{
+    let path = hint_path(dir, gen);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+    match scan(file, log_len, f)? {
+        Ok(end) => Ok(Some(end)),
+        Err(reason) => {
+            warn!("Ignoring {:?}: {}", path, reason);
+            Ok(None)
+        }
+    }
+}
+
+/// Scans the entries of a hint file of a log file with the given length, like
+/// `parse` does.
+fn scan<F>(file: File, log_len: u64, mut f: F) -> Result<::std::result::Result<u64, String>>
+where
+    F: FnMut(u64, &[u8]),
+{
+    let file_len = file.metadata()?.len();
+    if file_len < (MAGIC.len() + TRAILER_LEN) as u64 {
+        return Ok(Err("not a hint file".to_owned()));
+    }
+    let mut reader = BufReader::new(file);
+    let mut hasher = Hasher::new();
+    let mut read = |buf: &mut [u8]| -> io::Result<()> {
+        reader.read_exact(buf)?;
+        hasher.update(buf);
+        Ok(())
+    };
+
+    let mut magic = [0; MAGIC.len()];
+    read(&mut magic)?;
+    if &magic != MAGIC {
+        return Ok(Err("not a hint file".to_owned()));
+    }
+    let end = file_len - TRAILER_LEN as u64;
+    let mut offset = MAGIC.len() as u64;
+    let mut header = [0; ENTRY_HEADER_LEN];
+    let mut key = Vec::new();
+    while offset < end {
+        if offset + ENTRY_HEADER_LEN as u64 > end {
+            return Ok(Err("truncated entry".to_owned()));
+        }
+        read(&mut header)?;
+        let entry_len = (ENTRY_HEADER_LEN + read_u32(&header) as usize) as u64;
+        if offset + entry_len > end {
+            return Ok(Err("truncated entry".to_owned()));
+        }
+        key.resize(read_u32(&header) as usize, 0);
+        read(&mut key)?;
+        f(offset, &key);
+        offset += entry_len;
+    }
+
+    let mut log_len_bytes = [0; 8];
+    read(&mut log_len_bytes)?;
+    let mut crc = [0; 4];
+    reader.read_exact(&mut crc)?;
+    if hasher.finalize() != read_u32(&crc) {
+        return Ok(Err("checksum mismatch".to_owned()));
+    }
+    if read_u64(&log_len_bytes) != log_len {
+        return Ok(Err("log file length mismatch".to_owned()));
+    }
+    Ok(Ok(end))
+}
+
+/// Removes the hint file of a log file if there is one.
+pub(super) fn remove_hint_file(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+/// Parses the entries of a hint file of a log file with the given length.
+///
+/// The generation numbers of the returned positions are not set.
+fn parse(data: &[u8], log_len: u64) -> ::std::result::Result<Vec<(Vec<u8>, CommandPos)>, String> {
+    if data.len() < MAGIC.len() + TRAILER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err("not a hint file".to_owned());
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != read_u32(crc) {
+        return Err("checksum mismatch".to_owned());
+    }
+    let (entries, log_len_bytes) = body.split_at(body.len() - 8);
+    if read_u64(log_len_bytes) != log_len {
+        return Err("log file length mismatch".to_owned());
+    }
+    parse_entries(&entries[MAGIC.len()..])
+}
+
+/// Parses consecutive entries of a hint file.
+///
+/// The generation numbers of the returned positions are not set.
+pub(super) fn parse_entries(
+    mut entries: &[u8],
+) -> ::std::result::Result<Vec<(Vec<u8>, CommandPos)>, String> {
+    let mut parsed = Vec::new();
+    while !entries.is_empty() {
+        if entries.len() < ENTRY_HEADER_LEN {
+            return Err("truncated entry".to_owned());
+        }
+        let key_len = read_u32(entries) as usize;
+        let pos = read_u64(&entries[4..]);
+        let len = read_u64(&entries[12..]);
+        let expires_at = Some(read_u64(&entries[20..])).filter(|&expires_at| expires_at != 0);
+        entries = &entries[ENTRY_HEADER_LEN..];
+        if entries.len() < key_len {
+            return Err("truncated entry".to_owned());
+        }
+        let key = entries[..key_len].to_vec();
+        entries = &entries[key_len..];
+        let cmd_pos = CommandPos {
+            gen: 0,
+            pos,
+            len,
+            expires_at,
+        };
+        parsed.push((key, cmd_pos));
+    }
+    Ok(parsed)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
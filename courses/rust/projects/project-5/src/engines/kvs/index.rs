@@ -0,0 +1,332 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::{self, Peekable};
+use std::mem;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::key_file::KeyFile;
+use super::CommandPos;
+use crate::Result;
+
+/// How a `KvStore` indexes its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// All keys are kept in memory.
+    #[default]
+    Memory,
+    /// Only the keys written since the last compaction are kept in memory.
+    ///
+    /// The keys in the newest compaction file are looked up in its hint file,
+    /// which is sorted, through a sparse index of one in every 64 keys. So the
+    /// memory usage is bounded by the writes between compactions, and reads of
+    /// compacted keys take an extra read of the hint file.
+    ///
+    /// Log files older than the newest compaction file are ignored when the store
+    /// is opened, because the compaction file has all their live entries.
+    Sparse,
+}
+
+/// Maps keys to the positions of their "set" commands in the log.
+///
+/// In `IndexMode::Sparse`, entries in memory override the key file of the newest
+/// compaction file. Keys removed from the key file are hidden by tombstones until
+/// the next compaction.
+pub(super) struct Index {
+    mode: IndexMode,
+    memory: SkipMap<Vec<u8>, CommandPos>,
+    // keys removed since the last compaction and the generations of the removals
+    tombstones: SkipMap<Vec<u8>, u64>,
+    base: RwLock<Option<Arc<KeyFile>>>,
+    // the changes of the copies being made for snapshots
+    copies: Mutex<Vec<Arc<Mutex<Changes>>>>,
+}
+
+/// The states of the keys changed since a copy of the index started, as they were
+/// when it started.
+type Changes = BTreeMap<Vec<u8>, KeyState>;
+
+/// The entry in memory and the tombstone of a key.
+#[derive(Clone, Copy)]
+struct KeyState {
+    pos: Option<CommandPos>,
+    tombstone: Option<u64>,
+}
+
+/// A copy of an `Index` started by `Index::start_copy`.
+pub(super) struct IndexCopy {
+    base: Option<Arc<KeyFile>>,
+    changes: Arc<Mutex<Changes>>,
+}
+
+impl Index {
+    pub(super) fn new(mode: IndexMode) -> Index {
+        Index {
+            mode,
+            memory: SkipMap::new(),
+            tombstones: SkipMap::new(),
+            base: RwLock::new(None),
+            copies: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn mode(&self) -> IndexMode {
+        self.mode
+    }
+
+    /// Returns the position of the key.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        if let Some(entry) = self.memory.get(key) {
+            return Ok(Some(*entry.value()));
+        }
+        if self.tombstones.contains_key(key) {
+            return Ok(None);
+        }
+        match self.base() {
+            Some(base) => base.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Points the key to a new position.
+    ///
+    /// Returns the old position of the key if it's in memory. Keys in the key file
+    /// aren't looked up to keep reads off the write path, so replacing one isn't
+    /// counted as stale. That's at most one entry per key in the compaction file.
+    pub(super) fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos) -> Option<CommandPos> {
+        self.record_change(&key);
+        let old_pos = self.memory.get(&key).map(|entry| *entry.value());
+        // A tombstone of the key is kept, which is overridden by the entry in memory.
+        // Otherwise a concurrent reader could miss both and read the key file.
+        self.memory.insert(key, cmd_pos);
+        old_pos
+    }
+
+    /// Removes the key by a "remove" command in the given generation.
+    ///
+    /// Returns the old position of the key if it's in memory, like `insert`.
+    pub(super) fn remove(&self, key: &[u8], gen: u64) -> Option<CommandPos> {
+        self.record_change(key);
+        if self.mode == IndexMode::Sparse {
+            // The tombstone must be there before the entry in memory is gone. It's
+            // needed even without a key file, because a running compaction may
+            // have copied the key to the next one.
+            self.tombstones.insert(key.to_vec(), gen);
+        }
+        self.memory.remove(key).map(|entry| *entry.value())
+    }
+
+    /// Returns an iterator over the entries with keys in the range in ascending order.
+    pub(super) fn range(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, CommandPos)>> + '_ {
+        let base: Box<dyn Iterator<Item = _>> = match self.base() {
+            Some(base) => Box::new(KeyFile::iter_from(base, start.clone())),
+            None => Box::new(iter::empty()),
+        };
+        let base_end = end.clone();
+        let base = base
+            .take_while(move |entry| match (entry, &base_end) {
+                (Ok((key, _)), Bound::Included(end)) => key <= end,
+                (Ok((key, _)), Bound::Excluded(end)) => key < end,
+                _ => true,
+            })
+            .filter(move |entry| match entry {
+                Ok((key, _)) => !self.tombstones.contains_key(key),
+                Err(_) => true,
+            });
+        let memory = self
+            .memory
+            .range((start, end))
+            .map(|entry| (entry.key().clone(), *entry.value()));
+        Merge {
+            memory: memory.peekable(),
+            base: base.peekable(),
+        }
+    }
+
+    /// Starts a copy of the index, which is finished by `finish_copy`.
+    ///
+    /// It must be called with the writer locked, but the copy is made after the
+    /// writer is unlocked. Meanwhile writes record the states of the keys they
+    /// change, which the copy is corrected with.
+    pub(super) fn start_copy(&self) -> IndexCopy {
+        let changes = Arc::new(Mutex::new(Changes::new()));
+        self.copies.lock().unwrap().push(Arc::clone(&changes));
+        IndexCopy {
+            base: self.base(),
+            changes,
+        }
+    }
+
+    /// Copies the index as it was when the copy started, sharing the key file which
+    /// never changes.
+    ///
+    /// It takes time and memory in proportion to the keys in memory.
+    pub(super) fn finish_copy(&self, copy: IndexCopy) -> Index {
+        let index = Index::new(self.mode);
+        for entry in self.memory.iter() {
+            index.memory.insert(entry.key().clone(), *entry.value());
+        }
+        for entry in self.tombstones.iter() {
+            index.tombstones.insert(entry.key().clone(), *entry.value());
+        }
+        // The keys which aren't recorded haven't changed since the copy started, so
+        // they're copied right even if the entries above were read later.
+        let changes = {
+            let mut copies = self.copies.lock().unwrap();
+            copies.retain(|changes| !Arc::ptr_eq(changes, &copy.changes));
+            let mut changes = copy.changes.lock().unwrap();
+            mem::take(&mut *changes)
+        };
+        for (key, state) in changes {
+            match state.pos {
+                Some(pos) => {
+                    index.memory.insert(key.clone(), pos);
+                }
+                None => {
+                    index.memory.remove(&key);
+                }
+            }
+            match state.tombstone {
+                Some(gen) => {
+                    index.tombstones.insert(key, gen);
+                }
+                None => {
+                    index.tombstones.remove(&key);
+                }
+            }
+        }
+        *index.base.write().unwrap() = copy.base;
+        index
+    }
+
+    /// Records the state of a key before it's changed for the copies being made.
+    ///
+    /// It must be called with the writer locked.
+    fn record_change(&self, key: &[u8]) {
+        let copies = self.copies.lock().unwrap();
+        if copies.is_empty() {
+            return;
+        }
+        let state = KeyState {
+            pos: self.memory.get(key).map(|entry| *entry.value()),
+            tombstone: self.tombstones.get(key).map(|entry| *entry.value()),
+        };
+        for changes in copies.iter() {
+            // the first change of a key records its state when the copy started
+            changes.lock().unwrap().entry(key.to_vec()).or_insert(state);
+        }
+    }
+
+    /// Points the key to the position it's copied to by a compaction, or removes it
+    /// if `new_pos` is `None`, unless it has been changed since `old_pos` was read.
+    ///
+    /// Returns whether the key is updated. It must be called with the writer locked.
+    pub(super) fn move_entry(
+        &self,
+        key: Vec<u8>,
+        old_pos: CommandPos,
+        new_pos: Option<CommandPos>,
+    ) -> bool {
+        let unchanged = self.memory.get(&key).map(|entry| *entry.value()) == Some(old_pos);
+        if unchanged {
+            self.record_change(&key);
+            match new_pos {
+                Some(new_pos) => {
+                    self.memory.insert(key, new_pos);
+                }
+                None => {
+                    self.memory.remove(&key);
+                }
+            }
+        }
+        unchanged
+    }
+
+    /// Replaces the key file with the one of a new compaction file, which has all
+    /// live entries of older generations.
+    ///
+    /// The entries of older generations are dropped from memory along with the
+    /// tombstones of keys removed before the compaction. Returns the number of
+    /// bytes in the compaction file which are stale because their keys have been
+    /// changed since they were copied, and the dropped positions.
+    ///
+    /// It must be called with the writer locked.
+    pub(super) fn switch_base(&self, base: KeyFile) -> Result<(u64, Vec<CommandPos>)> {
+        let compaction_gen = base.gen();
+        let mut stale = 0;
+        for entry in self.memory.iter() {
+            if entry.value().gen > compaction_gen {
+                stale += base.get(entry.key())?.map_or(0, |cmd_pos| cmd_pos.len);
+            }
+        }
+        for entry in self.tombstones.iter() {
+            if *entry.value() > compaction_gen {
+                stale += base.get(entry.key())?.map_or(0, |cmd_pos| cmd_pos.len);
+            }
+        }
+
+        *self.base.write().unwrap() = Some(Arc::new(base));
+        let mut dropped = Vec::new();
+        for entry in self.memory.iter() {
+            if entry.value().gen < compaction_gen {
+                self.record_change(entry.key());
+                dropped.push(*entry.value());
+                entry.remove();
+            }
+        }
+        for entry in self.tombstones.iter() {
+            if *entry.value() < compaction_gen {
+                self.record_change(entry.key());
+                entry.remove();
+            }
+        }
+        Ok((stale, dropped))
+    }
+
+    /// Uses the key file of a compaction file when the store is opened.
+    pub(super) fn set_base(&self, base: KeyFile) {
+        *self.base.write().unwrap() = Some(Arc::new(base));
+    }
+
+    fn base(&self) -> Option<Arc<KeyFile>> {
+        self.base.read().unwrap().clone()
+    }
+}
+
+/// Merges the entries in memory with the ones in the key file, preferring the
+/// former for the same key.
+struct Merge<M: Iterator, B: Iterator> {
+    memory: Peekable<M>,
+    base: Peekable<B>,
+}
+
+impl<M, B> Iterator for Merge<M, B>
+where
+    M: Iterator<Item = (Vec<u8>, CommandPos)>,
+    B: Iterator<Item = Result<(Vec<u8>, CommandPos)>>,
+{
+    type Item = Result<(Vec<u8>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.memory.peek(), self.base.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+            (Some((key, _)), Some(Ok((base_key, _)))) => key.cmp(base_key),
+        };
+        match order {
+            Ordering::Less => self.memory.next().map(Ok),
+            Ordering::Greater => self.base.next(),
+            Ordering::Equal => {
+                self.base.next();
+                self.memory.next().map(Ok)
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::hint::hint_path;
+use super::index::{Index, IndexMode};
+use super::snapshot::{KvSnapshot, SnapshotRegistry};
+use super::{load, log_path, now_millis, sorted_gen_list, Compression, LogFormat, LogReader};
+use crate::Result;
+
+/// The statistics of a log file of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// The generation number of the log file.
+    pub gen: u64,
+    /// The format of the log file.
+    pub format: LogFormat,
+    /// The size of the log file in bytes.
+    pub size: u64,
+    /// The number of bytes of the commands in the log file that are in the index.
+    pub live: u64,
+    /// Whether the log file has a hint file.
+    pub has_hint: bool,
+}
+
+/// A corrupted record found in a log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptedRecord {
+    /// The generation number of the log file.
+    pub gen: u64,
+    /// The offset of the record in the log file.
+    pub offset: u64,
+    /// Why the record is considered corrupted.
+    pub reason: String,
+}
+
+/// Read-only access to the data directory of a `KvStore` which is not open.
+///
+/// All log files are replayed like `KvStore::open` does, ignoring hint files, but
+/// nothing in the directory is changed: corrupted records are only reported.
+pub struct KvStoreInspector {
+    path: Arc<PathBuf>,
+    index: Index,
+    generations: Vec<GenerationStats>,
+    corrupted: Vec<CorruptedRecord>,
+    uncompacted: u64,
+}
+
+impl KvStoreInspector {
+    /// Replays the log files in the given directory.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, including the one for a missing directory.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStoreInspector> {
+        let path = Arc::new(path.into());
+        fs::read_dir(&*path)?;
+
+        let index = Index::new(IndexMode::Memory);
+        let gen_list = sorted_gen_list(&path)?;
+        let mut files = Vec::new();
+        let mut corrupted = Vec::new();
+        let mut uncompacted = 0;
+        for (i, &gen) in gen_list.iter().enumerate() {
+            let mut reader = LogReader::open(&path, gen)?;
+            let replay = load(gen, &mut reader, &index, i + 1 == gen_list.len())?;
+            uncompacted += replay.uncompacted;
+            corrupted.extend(replay.corrupted.into_iter().map(|(offset, reason)| {
+                CorruptedRecord {
+                    gen,
+                    offset,
+                    reason,
+                }
+            }));
+            files.push((gen, reader.format));
+        }
+
+        let mut live = BTreeMap::new();
+        for entry in index.range(Bound::Unbounded, Bound::Unbounded) {
+            let (_, cmd_pos) = entry?;
+            *live.entry(cmd_pos.gen).or_insert(0) += cmd_pos.len;
+        }
+        let mut generations = Vec::new();
+        for (gen, format) in files {
+            generations.push(GenerationStats {
+                gen,
+                format,
+                size: fs::metadata(log_path(&path, gen))?.len(),
+                live: live.get(&gen).cloned().unwrap_or(0),
+                has_hint: hint_path(&path, gen).exists(),
+            });
+        }
+
+        Ok(KvStoreInspector {
+            path,
+            index,
+            generations,
+            corrupted,
+            uncompacted,
+        })
+    }
+
+    /// Returns the statistics of the log files in ascending order of generations.
+    pub fn generations(&self) -> &[GenerationStats] {
+        &self.generations
+    }
+
+    /// Returns the corrupted records found in the log files.
+    ///
+    /// The replay of the newest log file stops at its first corrupted record, which
+    /// `KvStore::open` truncates as a torn write.
+    pub fn corrupted(&self) -> &[CorruptedRecord] {
+        &self.corrupted
+    }
+
+    /// Returns the number of bytes of the commands in the index.
+    pub fn live_bytes(&self) -> u64 {
+        self.generations.iter().map(|stats| stats.live).sum()
+    }
+
+    /// Returns the number of bytes of stale commands that a compaction could save.
+    pub fn stale_bytes(&self) -> u64 {
+        self.uncompacted
+    }
+
+    /// Returns a snapshot to read the keys in the data directory.
+    pub fn snapshot(&self) -> KvSnapshot {
+        let format = self
+            .generations
+            .last()
+            .map_or_else(LogFormat::default, |stats| stats.format);
+        let registry = Arc::new(SnapshotRegistry::new(Arc::clone(&self.path)));
+        let id = registry.register();
+        let copy = self.index.start_copy();
+        KvSnapshot::new(
+            registry,
+            id,
+            self.index.finish_copy(copy),
+            format,
+            Compression::None,
+            now_millis(),
+        )
+    }
+}
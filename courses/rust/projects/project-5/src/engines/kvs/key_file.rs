@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::hint::{hint_path, parse_entries, scan_hint_file};
+use super::CommandPos;
+use crate::{KvsError, Result};
+
+/// The number of entries in a block of a key file. Only the first key of every
+/// block is kept in memory.
+const BLOCK_LEN: usize = 64;
+
+/// The sorted key file of a compaction file with a sparse index in memory.
+///
+/// The hint file of a compaction file lists its keys in ascending order, so it
+/// serves as the key file. A lookup finds the block which may contain the key by
+/// the first keys of the blocks and reads the block from the file.
+pub(super) struct KeyFile {
+    gen: u64,
+    file: Mutex<File>,
+    // the first key and the offset of every block
+    blocks: Vec<(Vec<u8>, u64)>,
+    // the offset where the entries end
+    end: u64,
+}
+
+impl KeyFile {
+    /// Opens the hint file of a compaction file as a key file.
+    ///
+    /// Returns `None` if the hint file is missing or invalid.
+    pub(super) fn open(dir: &Path, gen: u64) -> Result<Option<KeyFile>> {
+        let mut blocks = Vec::new();
+        let mut entries = 0;
+        let end = scan_hint_file(dir, gen, |offset, key| {
+            if entries % BLOCK_LEN == 0 {
+                blocks.push((key.to_vec(), offset));
+            }
+            entries += 1;
+        })?;
+        match end {
+            Some(end) => Ok(Some(KeyFile {
+                gen,
+                file: Mutex::new(File::open(hint_path(dir, gen))?),
+                blocks,
+                end,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the generation of the compaction file.
+    pub(super) fn gen(&self) -> u64 {
+        self.gen
+    }
+
+    /// Looks up the position of a key in the compaction file.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        match self.find_block(key) {
+            Some(block) => Ok(self
+                .read_block(block)?
+                .into_iter()
+                .find(|(block_key, _)| &block_key[..] == key)
+                .map(|(_, cmd_pos)| cmd_pos)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the entries with keys after `start` in ascending
+    /// order. Blocks are read lazily as the iterator advances.
+    pub(super) fn iter_from(
+        file: Arc<KeyFile>,
+        start: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, CommandPos)>> {
+        let first = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => file.find_block(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        (first..file.blocks.len())
+            .flat_map(move |block| match file.read_block(block) {
+                Ok(entries) => entries.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            })
+            .filter(move |entry| match (entry, &start) {
+                (Ok((key, _)), Bound::Included(start)) => key >= start,
+                (Ok((key, _)), Bound::Excluded(start)) => key > start,
+                _ => true,
+            })
+    }
+
+    /// Returns the block which may contain the key.
+    fn find_block(&self, key: &[u8]) -> Option<usize> {
+        match self
+            .blocks
+            .binary_search_by(|(first_key, _)| first_key[..].cmp(key))
+        {
+            Ok(block) => Some(block),
+            Err(0) => None,
+            Err(block) => Some(block - 1),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let start = self.blocks[block].1;
+        let end = self
+            .blocks
+            .get(block + 1)
+            .map_or(self.end, |&(_, offset)| offset);
+        let mut buf = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+        }
+        let entries = parse_entries(&buf).map_err(|reason| {
+            KvsError::StringError(format!(
+                "Key file of generation {} is corrupted: {}",
+                self.gen, reason
+            ))
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, cmd_pos)| {
+                (
+                    key,
+                    CommandPos {
+                        gen: self.gen,
+                        ..cmd_pos
+                    },
+                )
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,430 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crc32fast::Hasher;
+
+use super::Command;
+use crate::{KvsError, Result};
+
+/// Bytes at the beginning of every log file in the binary format.
+///
+/// JSON logs always start with `{`, so the magic number never collides with them.
+const MAGIC: &[u8; 8] = b"KVSLOG\x00\x01";
+
+/// Size of the fixed part of a binary record: checksum, type tag, key length and
+/// value length.
+const HEADER_LEN: usize = 4 + 1 + 4 + 4;
+
+const TAG_SET: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_BATCH: u8 = 3;
+const TAG_EXPIRING_SET: u8 = 4;
+
+/// Bits of the tag which tell how the value of a "set" record is compressed.
+const COMPRESSION_MASK: u8 = 0xc0;
+const FLAG_LZ4: u8 = 0x40;
+const FLAG_ZSTD: u8 = 0x80;
+
+/// Size of the expiration time before the key of an expiring "set" record.
+const EXPIRES_AT_LEN: usize = 8;
+
+/// The beginning of a batch serialized in JSON, which is followed by the commands
+/// in the batch separated by commas.
+const JSON_BATCH_PREFIX: &[u8] = b"{\"Batch\":[";
+
+/// The on-disk format of the records in a log file.
+///
+/// The format is detected per file when reading, so a data directory can contain
+/// log files of both formats. The format chosen at `KvStore::open` is only used
+/// for newly written files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Each command is a JSON object. This is the format used by earlier versions.
+    Json,
+    /// Each command is a length-prefixed binary record:
+    ///
+    /// ```text
+    /// | crc32 (4) | tag (1) | key len (4) | value len (4) | key | value |
+    /// ```
+    ///
+    /// All integers are little-endian. The checksum covers everything after itself.
+    ///
+    /// A batch is a record with an empty key whose value is the concatenated records
+    /// of the commands in the batch. A "set" command of a key with a TTL has a
+    /// different tag and the expiration time (8) between the header and the key.
+    ///
+    /// The two high bits of the tag of a "set" record tell how its value is
+    /// compressed, and the value length is the length of the compressed value.
+    #[default]
+    Binary,
+}
+
+/// The compression of values in newly written records.
+///
+/// Each record is flagged with its own compression, so a log file can contain
+/// records compressed in different ways. Values are stored uncompressed if the
+/// compression doesn't make them smaller. Only binary logs are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are not compressed.
+    #[default]
+    None,
+    /// Values are compressed with LZ4, which is fast but saves less space.
+    Lz4,
+    /// Values are compressed with Zstandard at its default level.
+    Zstd,
+}
+
+impl Compression {
+    fn flag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => FLAG_LZ4,
+            Compression::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    fn from_flag(flag: u8) -> Result<Compression> {
+        match flag {
+            0 => Ok(Compression::None),
+            FLAG_LZ4 => Ok(Compression::Lz4),
+            FLAG_ZSTD => Ok(Compression::Zstd),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    fn compress(self, value: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(value.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Compression::Zstd => Ok(zstd::bulk::compress(value, 0)?),
+        }
+    }
+
+    /// Decompresses a value compressed in this way.
+    pub(super) fn decompress(self, value: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(value),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&value)
+                .map_err(|e| KvsError::CorruptedLog(format!("{}", e))),
+            Compression::Zstd => Ok(zstd::stream::decode_all(&value[..])?),
+        }
+    }
+}
+
+impl LogFormat {
+    /// Compresses the values in a command to be written in this format.
+    ///
+    /// Values compressed in another way are decompressed first. JSON logs are
+    /// never compressed, so all values are decompressed for them.
+    pub(super) fn compress(self, cmd: Command, compression: Compression) -> Result<Command> {
+        let compression = match self {
+            LogFormat::Json => Compression::None,
+            LogFormat::Binary => compression,
+        };
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compression: current,
+            } if current != compression => {
+                let value = current.decompress(value)?;
+                let compressed = compression.compress(&value)?;
+                let (value, compression) = if compressed.len() < value.len() {
+                    (compressed, compression)
+                } else {
+                    (value, Compression::None)
+                };
+                Ok(Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    compression,
+                })
+            }
+            Command::Batch(cmds) => Ok(Command::Batch(
+                cmds.into_iter()
+                    .map(|cmd| self.compress(cmd, compression))
+                    .collect::<Result<_>>()?,
+            )),
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Writes the file header of this format to a newly created log file.
+    pub(super) fn write_file_header<W: Write>(self, writer: &mut W) -> Result<()> {
+        if self == LogFormat::Binary {
+            writer.write_all(MAGIC)?;
+        }
+        Ok(())
+    }
+
+    /// Detects the format of a log file by its header.
+    ///
+    /// The reader is left at the position of the first record.
+    pub(super) fn detect<R: Read + Seek>(reader: &mut R) -> Result<LogFormat> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0; MAGIC.len()];
+        let filled = read_to_fill(reader, &mut header)?;
+        if &header[..filled] == MAGIC {
+            Ok(LogFormat::Binary)
+        } else {
+            reader.seek(SeekFrom::Start(0))?;
+            Ok(LogFormat::Json)
+        }
+    }
+
+    /// Encodes a command into the writer.
+    pub(super) fn encode<W: Write>(self, cmd: &Command, writer: &mut W) -> Result<()> {
+        match self {
+            LogFormat::Json => serde_json::to_writer(writer, cmd)?,
+            LogFormat::Binary => writer.write_all(&encode_binary(cmd))?,
+        }
+        Ok(())
+    }
+
+    /// Returns the ranges of the commands in a batch, relative to the beginning of
+    /// the encoded batch.
+    ///
+    /// Each command in a batch is encoded the same way as a standalone command, so
+    /// it can be decoded from its own range.
+    pub(super) fn batch_ranges(self, cmds: &[Command]) -> Result<Vec<Range<u64>>> {
+        let (mut pos, separator_len) = match self {
+            LogFormat::Json => (JSON_BATCH_PREFIX.len() as u64, 1),
+            LogFormat::Binary => (HEADER_LEN as u64, 0),
+        };
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let len = match (self, cmd) {
+                (LogFormat::Json, _) => serde_json::to_vec(cmd)?.len(),
+                (
+                    LogFormat::Binary,
+                    Command::Set {
+                        key,
+                        value,
+                        expires_at,
+                        ..
+                    },
+                ) => {
+                    let extra = expires_at.map_or(0, |_| EXPIRES_AT_LEN);
+                    HEADER_LEN + extra + key.len() + value.len()
+                }
+                (LogFormat::Binary, Command::Remove { key }) => HEADER_LEN + key.len(),
+                (LogFormat::Binary, Command::Batch(_)) => {
+                    return Err(KvsError::UnexpectedCommandType)
+                }
+            } as u64;
+            ranges.push(pos..pos + len);
+            pos += len + separator_len;
+        }
+        Ok(ranges)
+    }
+
+    /// Decodes a single command from the reader.
+    pub(super) fn decode<R: Read>(self, mut reader: R) -> Result<Command> {
+        match self {
+            LogFormat::Json => Ok(serde_json::from_reader(reader)?),
+            LogFormat::Binary => read_binary_record(&mut reader)?.ok_or_else(|| {
+                KvsError::CorruptedLog("unexpected end of binary record".to_owned())
+            }),
+        }
+    }
+}
+
+/// Reads the next binary record.
+///
+/// Returns `None` if the reader is already at the end.
+///
+/// # Errors
+///
+/// It returns `KvsError::CorruptedLog` if the record is truncated or its checksum
+/// does not match. If the record is complete, the reader is left at the end of it
+/// so the caller can continue with the next record.
+pub(super) fn read_binary_record<R: Read>(reader: &mut R) -> Result<Option<Command>> {
+    let mut header = [0; HEADER_LEN];
+    match reader.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => {
+            if read_to_fill(reader, &mut header[1..])? < HEADER_LEN - 1 {
+                return Err(KvsError::CorruptedLog("truncated record header".to_owned()));
+            }
+        }
+    }
+    let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let tag = header[4] & !COMPRESSION_MASK;
+    let key_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    let value_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]) as usize;
+
+    // The lengths may be garbage, so don't allocate the buffer before reading
+    let extra_len = if tag == TAG_EXPIRING_SET {
+        EXPIRES_AT_LEN
+    } else {
+        0
+    };
+    let data_len = extra_len + key_len + value_len;
+    let mut data = Vec::new();
+    reader.take(data_len as u64).read_to_end(&mut data)?;
+    if data.len() < data_len {
+        return Err(KvsError::CorruptedLog("truncated record".to_owned()));
+    }
+    if checksum(&header[4..], &data) != crc {
+        return Err(KvsError::CorruptedLog("checksum mismatch".to_owned()));
+    }
+
+    let compression = Compression::from_flag(header[4] & COMPRESSION_MASK)?;
+    match tag {
+        TAG_SET => {
+            let value = data.split_off(key_len);
+            Ok(Some(Command::Set {
+                key: data,
+                value,
+                expires_at: None,
+                compression,
+            }))
+        }
+        TAG_EXPIRING_SET => {
+            let mut key = data.split_off(EXPIRES_AT_LEN);
+            let value = key.split_off(key_len);
+            let mut expires_at = [0; EXPIRES_AT_LEN];
+            expires_at.copy_from_slice(&data);
+            let expires_at = u64::from_le_bytes(expires_at);
+            Ok(Some(Command::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+                compression,
+            }))
+        }
+        TAG_REMOVE => {
+            data.truncate(key_len);
+            Ok(Some(Command::remove(data)))
+        }
+        TAG_BATCH => {
+            let mut body = &data[key_len..];
+            let mut cmds = Vec::new();
+            while let Some(cmd) = read_binary_record(&mut body)? {
+                cmds.push(cmd);
+            }
+            Ok(Some(Command::Batch(cmds)))
+        }
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+fn encode_binary(cmd: &Command) -> Vec<u8> {
+    let body;
+    let mut extra = None;
+    let (tag, key, value) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+            compression,
+        } => (TAG_SET | compression.flag(), &key[..], &value[..]),
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+            compression,
+        } => {
+            extra = Some(expires_at.to_le_bytes());
+            (TAG_EXPIRING_SET | compression.flag(), &key[..], &value[..])
+        }
+        Command::Remove { key } => (TAG_REMOVE, &key[..], &[][..]),
+        Command::Batch(cmds) => {
+            body = cmds.iter().flat_map(encode_binary).collect::<Vec<u8>>();
+            (TAG_BATCH, &[][..], &body[..])
+        }
+    };
+    let mut buf = Vec::with_capacity(HEADER_LEN + EXPIRES_AT_LEN + key.len() + value.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(tag);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    if let Some(extra) = &extra {
+        buf.extend_from_slice(extra);
+    }
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = checksum(&buf[4..HEADER_LEN], &buf[HEADER_LEN..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Reads until the buffer is filled or the end of the reader is reached.
+///
+/// Returns the number of bytes read.
+fn read_to_fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+fn checksum(header: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(header);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Serde helpers for keys and values in JSON records.
+///
+/// Bytes that are valid UTF-8 are written as strings, which is how earlier versions
+/// wrote keys and values, and other bytes are written as arrays of numbers. Both
+/// are accepted when reading.
+pub(super) mod json_bytes {
+    use std::fmt;
+    use std::str;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.collect_seq(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Vec<u8>, E> {
+            Ok(v.into_bytes())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
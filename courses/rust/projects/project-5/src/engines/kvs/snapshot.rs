@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+
+use super::cache::ValueCache;
+use super::hint::{remove_hint_file, HintWriter};
+use super::index::Index;
+use super::{
+    log_path, new_log_file, sorted_gen_list, CommandPos, Compression, KvStoreReader, LogFormat,
+};
+use crate::{KvPair, KvsError, Result};
+
+/// A consistent read-only view of a `KvStore` at the time it's taken.
+///
+/// Reads through a snapshot see neither later writes nor keys expiring after the
+/// snapshot is taken. While a snapshot is alive, compactions keep the stale log
+/// files it may read and delete them after the snapshot is dropped.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # async fn try_main() -> Result<()> {
+/// # let store: KvStore<RayonThreadPool> = KvStore::open(std::env::current_dir()?, 1)?;
+/// store.set(b"key".to_vec(), b"value1".to_vec()).await?;
+/// let snapshot = store.snapshot();
+/// store.set(b"key".to_vec(), b"value2".to_vec()).await?;
+/// assert_eq!(snapshot.get(b"key")?, Some(b"value1".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvSnapshot {
+    id: u64,
+    index: Index,
+    // format of log files written by the store
+    format: LogFormat,
+    // compression of records written by the store
+    compression: Compression,
+    // milliseconds since the Unix epoch when the snapshot is taken
+    taken_at: u64,
+    reader: KvStoreReader,
+    registry: Arc<SnapshotRegistry>,
+}
+
+impl KvSnapshot {
+    /// Makes a snapshot of the given copy of the index, registered with `id`.
+    pub(super) fn new(
+        registry: Arc<SnapshotRegistry>,
+        id: u64,
+        index: Index,
+        format: LogFormat,
+        compression: Compression,
+        taken_at: u64,
+    ) -> KvSnapshot {
+        let reader = KvStoreReader {
+            path: Arc::clone(&registry.path),
+            // the files of a snapshot are never stale
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+            // reads of a snapshot, e.g. by a backup, would evict the hot values of the store
+            cache: Arc::new(ValueCache::new(0)),
+        };
+        KvSnapshot {
+            id,
+            index,
+            format,
+            compression,
+            taken_at,
+            reader,
+            registry,
+        }
+    }
+
+    /// Gets the value of a given key in the snapshot.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key)? {
+            Some(cmd_pos) if !cmd_pos.is_expired(self.taken_at) => {
+                Ok(Some(self.reader.read_value(cmd_pos)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets the key/value pairs with keys in the range `[start, end)` in the snapshot.
+    ///
+    /// See `KvsEngine::scan` for details.
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KvPair>> {
+        if let Some(end) = end {
+            if end < start {
+                return Ok(Vec::new());
+            }
+        }
+        let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec()));
+        self.range(Bound::Included(start.to_vec()), end)
+            .take(limit.unwrap_or_else(usize::max_value))
+            .collect()
+    }
+
+    /// Gets the key/value pairs whose keys start with `prefix` in the snapshot.
+    ///
+    /// See `KvsEngine::scan_prefix` for details.
+    pub fn scan_prefix(&self, prefix: &[u8], limit: Option<usize>) -> Result<Vec<KvPair>> {
+        self.range(Bound::Included(prefix.to_vec()), Bound::Unbounded)
+            .take_while(|pair| match pair {
+                Ok((key, _)) => key.starts_with(prefix),
+                Err(_) => true,
+            })
+            .take(limit.unwrap_or_else(usize::max_value))
+            .collect()
+    }
+
+    /// Returns an iterator over all key/value pairs in the snapshot in ascending order
+    /// of the keys.
+    ///
+    /// Values are read lazily as the iterator advances.
+    pub fn iter(&self) -> impl Iterator<Item = Result<KvPair>> + '_ {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Returns the number of keys in the snapshot.
+    ///
+    /// In `IndexMode::Sparse`, the keys in the key file are read to count them.
+    pub fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        for entry in self.entries(Bound::Unbounded, Bound::Unbounded) {
+            entry?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    /// Returns whether the snapshot has no keys.
+    pub fn is_empty(&self) -> Result<bool> {
+        match self.entries(Bound::Unbounded, Bound::Unbounded).next() {
+            Some(entry) => entry.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Writes the keys in the snapshot to a new store in `dir`.
+    ///
+    /// The new store has a single compacted log file with a hint file, so it opens
+    /// fast. Values are compressed the way a compaction of the store would
+    /// compress them. Keys keep their expiration times. `dir` is created if it does not exist
+    /// and must not contain a store.
+    pub fn backup(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        if !sorted_gen_list(dir)?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{:?} already contains a store",
+                dir
+            )));
+        }
+
+        let mut writer = new_log_file(dir, 1, self.format)?;
+        let mut hint_writer = HintWriter::create(dir, 1)?;
+        for entry in self.entries(Bound::Unbounded, Bound::Unbounded) {
+            let (key, cmd_pos) = entry?;
+            let pos = writer.pos;
+            self.reader
+                .copy_command(cmd_pos, self.format, self.compression, &mut writer)?;
+            let new_pos = CommandPos {
+                expires_at: cmd_pos.expires_at,
+                ..(1, pos..writer.pos).into()
+            };
+            hint_writer.add(&key, new_pos)?;
+        }
+        writer.flush()?;
+        hint_writer.finish(writer.pos)?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn range(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<KvPair>> + '_ {
+        self.entries(start, end).map(move |entry| {
+            let (key, cmd_pos) = entry?;
+            Ok((key, self.reader.read_value(cmd_pos)?))
+        })
+    }
+
+    /// Returns the index entries in the range of the keys which haven't expired.
+    fn entries(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, CommandPos)>> + '_ {
+        self.index
+            .range(start, end)
+            .filter(move |entry| match entry {
+                Ok((_, cmd_pos)) => !cmd_pos.is_expired(self.taken_at),
+                Err(_) => true,
+            })
+    }
+}
+
+impl Drop for KvSnapshot {
+    fn drop(&mut self) {
+        self.registry.unregister(self.id);
+    }
+}
+
+/// Tracks the live snapshots of a `KvStore` and the stale log files they pin.
+pub(super) struct SnapshotRegistry {
+    path: Arc<PathBuf>,
+    state: Mutex<RegistryState>,
+}
+
+struct RegistryState {
+    next_id: u64,
+    live: BTreeSet<u64>,
+    // stale generations which can be removed after all snapshots with smaller ids
+    // are dropped
+    pinned: Vec<(u64, Vec<u64>)>,
+}
+
+impl SnapshotRegistry {
+    pub(super) fn new(path: Arc<PathBuf>) -> SnapshotRegistry {
+        SnapshotRegistry {
+            path,
+            state: Mutex::new(RegistryState {
+                next_id: 0,
+                live: BTreeSet::new(),
+                pinned: Vec::new(),
+            }),
+        }
+    }
+
+    /// Removes the stale log files of a compaction, or keeps them until the
+    /// snapshots that are alive now are dropped.
+    pub(super) fn remove_stale_files(&self, mut stale_gens: Vec<u64>) {
+        let mut state = self.state.lock().unwrap();
+        // files pinned by an earlier compaction are still there
+        stale_gens.retain(|gen| !state.pinned.iter().any(|(_, gens)| gens.contains(gen)));
+        if state.live.is_empty() {
+            drop(state);
+            self.remove_files(&stale_gens);
+        } else {
+            let barrier = state.next_id;
+            state.pinned.push((barrier, stale_gens));
+        }
+    }
+
+    /// Registers a new snapshot, whose log files are kept from now on.
+    ///
+    /// It must be called with the writer locked, when the copy of the index for the
+    /// snapshot starts.
+    pub(super) fn register(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.live.insert(id);
+        id
+    }
+
+    fn unregister(&self, id: u64) {
+        let unpinned: Vec<u64> = {
+            let mut state = self.state.lock().unwrap();
+            state.live.remove(&id);
+            let oldest = state.live.iter().next().cloned().unwrap_or(state.next_id);
+            let (unpinned, pinned) = state
+                .pinned
+                .drain(..)
+                .partition(|&(barrier, _)| barrier <= oldest);
+            state.pinned = pinned;
+            unpinned.into_iter().flat_map(|(_, gens)| gens).collect()
+        };
+        self.remove_files(&unpinned);
+    }
+
+    // Note that actually these files are not deleted immediately if `KvStoreReader`s
+    // still keep open file handles. On Unix, the files will be deleted after all the
+    // handles are closed. On Windows, the deletions below will fail and stale files
+    // are expected to be deleted in the next compaction.
+    fn remove_files(&self, gens: &[u64]) {
+        for &gen in gens {
+            let file_path = log_path(&self.path, gen);
+            if let Err(e) = fs::remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+            if let Err(e) = remove_hint_file(&self.path, gen) {
+                error!("Hint file of {:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::{KvsError, Result};
+
+/// When writes to a `KvStore` are synced to the disk.
+///
+/// Writes are always flushed to the OS before they are acknowledged, so they survive
+/// a crash of the process. Syncing makes them survive a crash of the OS too.
+///
+/// It can be parsed from `always`, `never` or a sync interval in milliseconds like
+/// `10ms`:
+///
+/// ```rust
+/// # use kvs::SyncPolicy;
+/// # use std::time::Duration;
+/// let policy: SyncPolicy = "10ms".parse().unwrap();
+/// assert_eq!(policy, SyncPolicy::Interval(Duration::from_millis(10)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Each write is synced before it's acknowledged.
+    Always,
+    /// Group commit. Writes are acknowledged after they are synced, but at most one
+    /// sync is done in each interval and it covers all the writes waiting for it.
+    Interval(Duration),
+    /// Writes are only synced by `KvsEngine::flush` and when the store switches to a
+    /// new log file.
+    #[default]
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<SyncPolicy, String> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => s
+                .trim_end_matches("ms")
+                .parse()
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| format!("invalid sync policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Syncs the current log file according to the `SyncPolicy`.
+///
+/// Every write gets a sequence number from `Syncer::written`, and the writer waits
+/// for it with `Syncer::wait` after the writer is unlocked. In the group commit
+/// mode, a dedicated thread syncs the file for all writes done so far at most once
+/// in each interval, so waiting writes don't hold threads of the thread pool.
+pub(super) struct Syncer {
+    policy: SyncPolicy,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<SyncState>,
+    // signaled when a write starts waiting or the syncer is dropped
+    wakeup: Condvar,
+}
+
+struct SyncState {
+    // a handle of the log file being written
+    file: Arc<File>,
+    // sequence number of the last write
+    written: u64,
+    // sequence number of the last synced write
+    synced: u64,
+    // the sequence numbers of the writes waiting to be synced and their senders
+    waiters: Vec<(u64, oneshot::Sender<io::Result<()>>)>,
+    closed: bool,
+}
+
+impl Syncer {
+    pub(super) fn new(policy: SyncPolicy, file: &File) -> Result<Syncer> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SyncState {
+                file: Arc::new(file.try_clone()?),
+                written: 0,
+                synced: 0,
+                waiters: Vec::new(),
+                closed: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+        if let SyncPolicy::Interval(interval) = policy {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("kvs-group-commit".to_owned())
+                .spawn(move || group_commit(&shared, interval))?;
+        }
+        Ok(Syncer { policy, shared })
+    }
+
+    /// Records a write flushed to `file`. It must be called with the writer locked.
+    ///
+    /// Returns the sequence number of the write.
+    pub(super) fn written(&self, file: &File) -> Result<u64> {
+        if self.policy == SyncPolicy::Always {
+            file.sync_data()?;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        state.written += 1;
+        Ok(state.written)
+    }
+
+    /// Switches to a new log file. It must be called with the writer locked.
+    ///
+    /// The old file is synced first, whatever the sync policy is, because later
+    /// syncs, including the ones of `KvsEngine::flush`, only cover the new file.
+    pub(super) fn switch_file(&self, old: &File, new: &File) -> Result<()> {
+        old.sync_data()?;
+        self.shared.state.lock().unwrap().file = Arc::new(new.try_clone()?);
+        Ok(())
+    }
+
+    /// Waits until the write with the given sequence number is synced.
+    pub(super) async fn wait(&self, seq: u64) -> Result<()> {
+        if let SyncPolicy::Always | SyncPolicy::Never = self.policy {
+            return Ok(());
+        }
+        let synced = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.synced >= seq {
+                return Ok(());
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push((seq, tx));
+            rx
+        };
+        self.shared.wakeup.notify_one();
+        match synced.await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(KvsError::StringError(
+                "The group commit thread stopped".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.wakeup.notify_one();
+    }
+}
+
+/// Syncs the file whenever writes are waiting, at most once in each interval, until
+/// the syncer is dropped.
+fn group_commit(shared: &Shared, interval: Duration) {
+    let mut last_sync = Instant::now();
+    loop {
+        {
+            let mut state = shared.state.lock().unwrap();
+            while state.waiters.is_empty() && !state.closed {
+                state = shared.wakeup.wait(state).unwrap();
+            }
+            if state.waiters.is_empty() {
+                return;
+            }
+        }
+
+        // Writes done while sleeping are synced together.
+        let elapsed = last_sync.elapsed();
+        if elapsed < interval {
+            thread::sleep(interval - elapsed);
+        }
+        let (file, target) = {
+            let state = shared.state.lock().unwrap();
+            (Arc::clone(&state.file), state.written)
+        };
+        let res = file.sync_data();
+        last_sync = Instant::now();
+
+        let done = {
+            let mut state = shared.state.lock().unwrap();
+            if res.is_ok() {
+                state.synced = target;
+            }
+            let (done, waiting) = mem::take(&mut state.waiters)
+                .into_iter()
+                .partition(|&(seq, _)| seq <= target);
+            state.waiters = waiting;
+            done
+        };
+        for (_, tx) in done {
+            let res = match &res {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            // the write may have been cancelled
+            let _ = tx.send(res);
+        }
+    }
+}
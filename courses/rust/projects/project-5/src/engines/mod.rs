@@ -1,28 +1,165 @@
-pub use self::kvs::KvStore;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kvs::{
+    CacheStats, CompactionStats, Compression, CorruptedRecord, GenerationStats, IndexMode,
+    KvSnapshot, KvStore, KvStoreInspector, KvStoreOptions, LogFormat, SyncPolicy,
+};
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+use crate::thread_pool::{ThreadPool, ThreadPoolStats};
+use crate::{KvsError, Result};
 
-use tokio::prelude::Future;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
 
+mod batch;
 mod kvs;
 mod sled;
 
+/// A key/value pair returned by scans.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// The statistics of a storage engine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of bytes the data takes on the disk.
+    pub disk_usage: u64,
+    /// The jobs of the thread pool the operations run in.
+    pub thread_pool: ThreadPoolStats,
+    /// The background compaction, if the engine compacts its log.
+    pub compaction: Option<CompactionStats>,
+    /// The value cache, if the engine has one.
+    pub cache: Option<CacheStats>,
+}
+
 /// Trait for a key value storage engine.
-pub trait KvsEngine: Clone + Send + 'static {
-    /// Sets the value of a string key to a string.
+///
+/// Keys and values are arbitrary bytes. Keys are ordered lexicographically by bytes.
+#[async_trait]
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// An expired key is treated as if it does not exist. Setting the key again
+    /// without a TTL makes it persistent.
+    async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a key along with the time left until it expires, which is
+    /// `None` if the key has no TTL.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    /// Returns `None` if the key does not exist or has expired.
+    async fn get_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    async fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Applies all operations in a `WriteBatch` atomically.
+    ///
+    /// Either all operations in the batch take effect or none of them do, even if
+    /// the process crashes while writing the batch.
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets the value of a key to `new` if its current value is `expected`, atomically.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new` removes
+    /// the key. A new value never expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConditionFailed` if the current value is not `expected`.
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Gets the key/value pairs with keys in the range `[start, end)` in ascending
+    /// order of the keys.
+    ///
+    /// If `end` is `None`, the range is unbounded. At most `limit` pairs are returned
+    /// if `limit` is specified.
+    async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KvPair>>;
+
+    /// Gets the keys in the range `[start, end)` in ascending order, like `scan`
+    /// without reading the values.
+    async fn scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>>;
+
+    /// Gets the key/value pairs whose keys start with `prefix` in ascending order
+    /// of the keys.
+    ///
+    /// At most `limit` pairs are returned if `limit` is specified.
+    async fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>>;
+
+    /// Writes a consistent, compacted copy of the store to `dir` while it keeps serving.
+    ///
+    /// `dir` is created if it does not exist. The copy can be opened as a store of the
+    /// same engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if `dir` already contains data.
+    async fn backup(&self, dir: PathBuf) -> Result<()>;
+
+    /// Flushes all writes to the disk.
+    ///
+    /// The writes are durable after it returns regardless of the sync policy.
+    async fn flush(&self) -> Result<()>;
+
+    /// Returns the statistics of the engine.
+    async fn stats(&self) -> Result<EngineStats>;
+}
+
+/// Runs `job` in the thread pool, so the async runtime is not blocked by disk I/O.
+///
+/// The job is spawned immediately. The returned future resolves to its result.
+fn run_in_pool<P, F, T>(pool: &P, job: F) -> impl Future<Output = Result<T>>
+where
+    P: ThreadPool,
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        if tx.send(job()).is_err() {
+            error!("Receiving end is dropped");
+        }
+    });
+    async move {
+        rx.await
+            .map_err(|e| KvsError::StringError(format!("{}", e)))?
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
@@ -1,14 +1,47 @@
-use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
-use tokio::prelude::*;
-use tokio::sync::oneshot;
+use super::{now_millis, run_in_pool, EngineStats};
+use crate::thread_pool::{InstrumentedThreadPool, ThreadPool};
+use crate::{BatchOp, KvPair, KvsEngine, KvsError, Result, WriteBatch};
+use async_trait::async_trait;
+use sled::{Db, IVec, Tree};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Name of the tree storing the expiration times of keys with a TTL.
+const EXPIRY_TREE: &[u8] = b"kvs_expiry";
+
+/// Name of the tree storing the changes of a write which is being applied.
+const PENDING_TREE: &[u8] = b"kvs_pending";
+
+/// Key of the changes in the pending tree.
+const PENDING_KEY: &[u8] = b"changes";
+
+/// How often expired keys are removed from both trees, in milliseconds.
+const SWEEP_INTERVAL: u64 = 60_000;
 
 /// Wrapper of `sled::Db`
+///
+/// Expiration times of keys with a TTL are kept in a separate tree, in milliseconds
+/// since the Unix epoch. Expired keys are hidden from reads, and removed from both
+/// trees by `remove_expired`, which runs on `flush` and in the thread pool at most
+/// once a minute after keys with a TTL are set.
+///
+/// This version of sled has no transactions, so writes hold the engine exclusively
+/// while reads share it. A write which changes more than one entry, like a batch, is
+/// saved to a separate tree before it's applied, and applied again when the database
+/// is opened if it was interrupted by a crash.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
-    pool: P,
-    db: Db,
+    pool: InstrumentedThreadPool<P>,
+    trees: Trees,
+    // held exclusively by writes and shared by reads and backups
+    lock: Arc<RwLock<()>>,
+    // directory of the database if it was opened by `open`
+    path: Option<Arc<Path>>,
+    // when expired keys were last removed
+    last_sweep: Arc<AtomicU64>,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -16,69 +49,572 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     ///
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
+    ///
+    /// The directory of the database is unknown to the engine, so its disk usage is
+    /// reported as 0.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
-        let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        SledKvsEngine::with_path(db, None, concurrency)
     }
-}
 
-impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
+    /// Opens the sled database in `path` and creates a `SledKvsEngine` from it.
+    pub fn open(path: impl AsRef<Path>, concurrency: u32) -> Result<Self> {
+        let path = path.as_ref();
+        let db = Db::start_default(path)?;
+        SledKvsEngine::with_path(db, Some(path.into()), concurrency)
+    }
+
+    fn with_path(db: Db, path: Option<Arc<Path>>, concurrency: u32) -> Result<Self> {
+        let pool = InstrumentedThreadPool::new(concurrency)?;
+        let trees = Trees {
+            expiry: db.open_tree(EXPIRY_TREE)?,
+            pending: db.open_tree(PENDING_TREE)?,
+            db,
+        };
+        trees.recover()?;
+        Ok(SledKvsEngine {
+            pool,
+            trees,
+            lock: Arc::new(RwLock::new(())),
+            path,
+            last_sweep: Arc::new(AtomicU64::new(now_millis())),
+        })
+    }
+
+    /// Removes the expired keys from both trees.
+    ///
+    /// Returns the number of removed keys.
+    pub async fn remove_expired(&self) -> Result<usize> {
+        self.last_sweep.store(now_millis(), Ordering::Relaxed);
+        self.write(Trees::sweep).await
+    }
+
+    /// Removes the expired keys in the thread pool if they haven't been removed for
+    /// `SWEEP_INTERVAL`.
+    fn sweep_if_due(&self) {
+        let now = now_millis();
+        let last_sweep = self.last_sweep.load(Ordering::Relaxed);
+        let due = now.saturating_sub(last_sweep) >= SWEEP_INTERVAL
+            && self
+                .last_sweep
+                .compare_exchange(last_sweep, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok();
+        if !due {
+            return;
+        }
+        let trees = self.trees.clone();
+        let lock = self.lock.clone();
         self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+            let res = {
+                let _lock = lock.write().unwrap();
+                trees.sweep()
+            };
+            if let Err(e) = res.and_then(|_| trees.flush()) {
+                error!("Error on removing expired keys: {}", e);
             }
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
     }
 
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+    /// Sets the value of a key, which expires at `expires_at` if specified.
+    async fn set_expiring(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        if expires_at.is_some() {
+            self.sweep_if_due();
+        }
+        self.write(move |trees| {
+            let mut changes = Vec::new();
+            if expires_at.is_some() || trees.expiry.contains_key(&key)? {
+                changes.push(Change::Expiry {
+                    key: key.clone(),
+                    expires_at,
+                });
             }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+            changes.push(Change::Data {
+                key,
+                value: Some(value),
+            });
+            trees.commit(changes)
+        })
+        .await
     }
 
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
-                Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+    /// Runs `f` in the thread pool with the engine shared.
+    async fn read<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Trees) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let trees = self.trees.clone();
+        let lock = self.lock.clone();
+        run_in_pool(&self.pool, move || {
+            let _lock = lock.read().unwrap();
+            f(&trees)
+        })
+        .await
+    }
+
+    /// Runs `f` in the thread pool with the engine held exclusively, and flushes the
+    /// database after it.
+    async fn write<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Trees) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let trees = self.trees.clone();
+        let lock = self.lock.clone();
+        run_in_pool(&self.pool, move || {
+            let res = {
+                let _lock = lock.write().unwrap();
+                f(&trees)
+            };
+            trees.flush()?;
+            res
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
+    async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_expiring(key, value, None).await
+    }
+
+    async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.set_expiring(key, value, Some(expires_at)).await
+    }
+
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read(move |trees| {
+            if trees.is_expired(&key, now_millis())? {
+                return Ok(None);
             }
+            Ok(trees.db.get(&key)?.map(|value| value.to_vec()))
+        })
+        .await
+    }
+
+    async fn get_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        self.read(move |trees| {
+            let now = now_millis();
+            let ttl = match trees.expires_at(&key)? {
+                Some(expires_at) if expires_at <= now => return Ok(None),
+                Some(expires_at) => Some(Duration::from_millis(expires_at - now)),
+                None => None,
+            };
+            Ok(trees.db.get(&key)?.map(|value| (value.to_vec(), ttl)))
+        })
+        .await
+    }
+
+    async fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.write(move |trees| {
+            let now = now_millis();
+            let expires_at = trees.expires_at(&key)?;
+            let exists = trees.db.contains_key(&key)?;
+            let mut changes = Vec::new();
+            if expires_at.is_some() {
+                changes.push(Change::Expiry {
+                    key: key.clone(),
+                    expires_at: None,
+                });
+            }
+            if exists {
+                changes.push(Change::Data { key, value: None });
+            }
+            trees.commit(changes)?;
+            // An expired key is removed as well, but it's not found for the user
+            match expires_at {
+                Some(expires_at) if expires_at <= now => Err(KvsError::KeyNotFound),
+                _ if !exists => Err(KvsError::KeyNotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(move |trees| {
+            let mut changes = Vec::new();
+            for op in batch.ops() {
+                let (key, value) = match op {
+                    BatchOp::Set { key, value } => (key, Some(value.clone())),
+                    BatchOp::Remove { key } => (key, None),
+                };
+                if trees.expiry.contains_key(key)? {
+                    changes.push(Change::Expiry {
+                        key: key.clone(),
+                        expires_at: None,
+                    });
+                }
+                changes.push(Change::Data {
+                    key: key.clone(),
+                    value,
+                });
+            }
+            trees.commit(changes)
+        })
+        .await
+    }
+
+    /// Sets or removes a key if its current value is `expected`.
+    ///
+    /// `sled::Tree::cas` can't see the expiry tree, so the comparison is done with the
+    /// engine held exclusively instead.
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.write(move |trees| {
+            let current = if trees.is_expired(&key, now_millis())? {
+                None
+            } else {
+                trees.db.get(&key)?
+            };
+            if current.as_ref().map(|v| &v[..]) != expected.as_ref().map(|v| &v[..]) {
+                return Err(KvsError::ConditionFailed);
+            }
+            let mut changes = Vec::new();
+            if trees.expiry.contains_key(&key)? {
+                changes.push(Change::Expiry {
+                    key: key.clone(),
+                    expires_at: None,
+                });
+            }
+            changes.push(Change::Data { key, value: new });
+            trees.commit(changes)
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<KvPair>> {
+        self.read(move |trees| match end {
+            Some(end) => collect_pairs(trees.db.range(start..end), trees, limit),
+            None => collect_pairs(trees.db.range(start..), trees, limit),
+        })
+        .await
+    }
+
+    async fn scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.read(move |trees| {
+            let key = |key, _| key;
+            match end {
+                Some(end) => collect_live(trees.db.range(start..end), trees, limit, key),
+                None => collect_live(trees.db.range(start..), trees, limit, key),
+            }
+        })
+        .await
+    }
+
+    async fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        self.read(move |trees| {
+            let iter = trees.db.scan(&prefix).take_while(|res| match res {
+                Ok((key, _)) => key.starts_with(&prefix),
+                Err(_) => true,
+            });
+            collect_pairs(iter, trees, limit)
+        })
+        .await
+    }
+
+    /// Copies the data and the expiration times to a new sled database in `dir`.
+    ///
+    /// Writes wait until the backup finishes because sled has no snapshots, while
+    /// reads continue.
+    async fn backup(&self, dir: PathBuf) -> Result<()> {
+        self.read(move |trees| {
+            if dir.exists() && fs::read_dir(&dir)?.next().is_some() {
+                return Err(KvsError::StringError(format!(
+                    "{:?} already contains data",
+                    dir
+                )));
+            }
+            let backup = Db::start_default(&dir)?;
+            copy_tree(&trees.db, &backup)?;
+            copy_tree(&trees.expiry, &*backup.open_tree(EXPIRY_TREE)?)?;
+            backup.flush()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the expired keys and flushes the database.
+    async fn flush(&self) -> Result<()> {
+        self.remove_expired().await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        let path = self.path.clone();
+        let disk_usage = run_in_pool(&self.pool, move || match path {
+            Some(path) => dir_size(&path),
+            None => Ok(0),
+        })
+        .await?;
+        Ok(EngineStats {
+            disk_usage,
+            thread_pool: self.pool.stats(),
+            compaction: None,
+            cache: None,
+        })
+    }
+}
+
+/// The trees of a `SledKvsEngine`.
+#[derive(Clone)]
+struct Trees {
+    db: Db,
+    expiry: Arc<Tree>,
+    pending: Arc<Tree>,
+}
+
+impl Trees {
+    /// Applies the changes of a write which was interrupted by a crash, if any.
+    fn recover(&self) -> Result<()> {
+        if let Some(changes) = self.pending.get(PENDING_KEY)? {
+            self.apply(decode_changes(&changes)?)?;
+            self.pending.del(PENDING_KEY)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the changes so that either all or none of them are recovered after a
+    /// crash.
+    ///
+    /// It must be called with the engine held exclusively.
+    fn commit(&self, changes: Vec<Change>) -> Result<()> {
+        if changes.len() <= 1 {
+            return self.apply(changes);
+        }
+        self.pending.set(PENDING_KEY, encode_changes(&changes))?;
+        self.flush()?;
+        self.apply(changes)?;
+        self.pending.del(PENDING_KEY)?;
+        Ok(())
+    }
+
+    fn apply(&self, changes: Vec<Change>) -> Result<()> {
+        for change in changes {
+            match change {
+                Change::Data {
+                    key,
+                    value: Some(value),
+                } => self.db.set(key, value)?,
+                Change::Data { key, value: None } => self.db.del(key)?,
+                Change::Expiry {
+                    key,
+                    expires_at: Some(expires_at),
+                } => self.expiry.set(key, &expires_at.to_be_bytes()[..])?,
+                Change::Expiry {
+                    key,
+                    expires_at: None,
+                } => self.expiry.del(key)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// Removes the keys which have expired from both trees.
+    ///
+    /// Returns the number of removed keys. It must be called with the engine held
+    /// exclusively.
+    fn sweep(&self) -> Result<usize> {
+        let now = now_millis();
+        let mut changes = Vec::new();
+        for entry in self.expiry.iter() {
+            let (key, expires_at) = entry?;
+            if decode_millis(&expires_at) <= now {
+                changes.push(Change::Expiry {
+                    key: key.clone(),
+                    expires_at: None,
+                });
+                changes.push(Change::Data { key, value: None });
+            }
+        }
+        let removed = changes.len() / 2;
+        self.commit(changes)?;
+        Ok(removed)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Returns the expiration time of a key, if it has one.
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.expiry.get(key)?.map(|bytes| decode_millis(&bytes)))
+    }
+
+    /// Returns whether a key has expired. A key without an expiration time never
+    /// expires.
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(self
+            .expires_at(key)?
+            .is_some_and(|expires_at| expires_at <= now))
+    }
+}
+
+/// A change of an entry in the data tree or the expiry tree, which is removed if the
+/// new value is `None`.
+enum Change {
+    Data {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+    Expiry {
+        key: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+}
+
+/// Encodes changes to be saved in the pending tree.
+///
+/// Each change starts with a tag byte followed by the key and the new value, if any.
+/// Keys and data values are prefixed with their lengths in 8 big-endian bytes.
+fn encode_changes(changes: &[Change]) -> Vec<u8> {
+    fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        buf.extend_from_slice(bytes);
+    }
+
+    let mut buf = Vec::new();
+    for change in changes {
+        match change {
+            Change::Data { key, value } => {
+                buf.push(if value.is_some() { 0 } else { 1 });
+                put_bytes(&mut buf, key);
+                if let Some(value) = value {
+                    put_bytes(&mut buf, value);
+                }
+            }
+            Change::Expiry { key, expires_at } => {
+                buf.push(if expires_at.is_some() { 2 } else { 3 });
+                put_bytes(&mut buf, key);
+                if let Some(expires_at) = expires_at {
+                    buf.extend_from_slice(&expires_at.to_be_bytes());
+                }
+            }
+        }
+    }
+    buf
+}
+
+/// Decodes changes encoded by `encode_changes`.
+fn decode_changes(mut buf: &[u8]) -> Result<Vec<Change>> {
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if buf.len() < len {
+            return Err(KvsError::StringError(
+                "Pending changes of the sled database are corrupted".to_owned(),
+            ));
+        }
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        Ok(head)
+    }
+
+    fn take_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+        let len = decode_millis(take(buf, 8)?) as usize;
+        Ok(take(buf, len)?.to_vec())
+    }
+
+    let mut changes = Vec::new();
+    while !buf.is_empty() {
+        let tag = take(&mut buf, 1)?[0];
+        let key = take_bytes(&mut buf)?;
+        changes.push(match tag {
+            0 => Change::Data {
+                key,
+                value: Some(take_bytes(&mut buf)?),
+            },
+            1 => Change::Data { key, value: None },
+            2 => Change::Expiry {
+                key,
+                expires_at: Some(decode_millis(take(&mut buf, 8)?)),
+            },
+            _ => Change::Expiry {
+                key,
+                expires_at: None,
+            },
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
     }
+    Ok(changes)
+}
+
+/// Decodes an expiration time in the expiry tree.
+fn decode_millis(bytes: &[u8]) -> u64 {
+    let mut millis = [0; 8];
+    millis.copy_from_slice(bytes);
+    u64::from_be_bytes(millis)
+}
+
+/// Copies all entries of a tree to another.
+fn copy_tree(from: &Tree, to: &Tree) -> Result<()> {
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        to.set(key, value)?;
+    }
+    Ok(())
+}
+
+/// Returns the total size of the files in a directory and its subdirectories.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Collects at most `limit` key/value pairs which are not expired from a sled iterator.
+fn collect_pairs<I>(iter: I, trees: &Trees, limit: Option<usize>) -> Result<Vec<KvPair>>
+where
+    I: Iterator<Item = sled::Result<(Vec<u8>, IVec)>>,
+{
+    collect_live(iter, trees, limit, |key, value| (key, value.to_vec()))
+}
+
+/// Collects at most `limit` items made by `f` from the key/value pairs which are not
+/// expired in a sled iterator.
+fn collect_live<I, F, T>(iter: I, trees: &Trees, limit: Option<usize>, f: F) -> Result<Vec<T>>
+where
+    I: Iterator<Item = sled::Result<(Vec<u8>, IVec)>>,
+    F: Fn(Vec<u8>, IVec) -> T,
+{
+    let now = now_millis();
+    let limit = limit.unwrap_or_else(usize::max_value);
+    let mut items = Vec::new();
+    for res in iter {
+        if items.len() >= limit {
+            break;
+        }
+        let (key, value) = res?;
+        if !trees.is_expired(&key, now)? {
+            items.push(f(key, value));
+        }
+    }
+    Ok(items)
 }
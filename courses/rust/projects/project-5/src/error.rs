@@ -1,3 +1,6 @@
+// `failure_derive` implements `Fail` and `Display` in named constants, which
+// newer compilers warn about
+#![allow(non_local_definitions)]
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
@@ -14,16 +17,25 @@ pub enum KvsError {
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// The current value of a key is not the expected one in a compare-and-swap
+    #[fail(display = "Condition failed")]
+    ConditionFailed,
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A record in the log is corrupted.
+    #[fail(display = "Corrupted log: {}", _0)]
+    CorruptedLog(String),
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// TLS error
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(#[cause] tokio_rustls::rustls::Error),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -53,5 +65,11 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<tokio_rustls::rustls::Error> for KvsError {
+    fn from(err: tokio_rustls::rustls::Error) -> KvsError {
+        KvsError::Tls(err)
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -4,14 +4,22 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use client::{KvsClient, KvsClientOptions};
+pub use engines::{
+    BatchOp, CacheStats, CompactionStats, Compression, CorruptedRecord, EngineStats,
+    GenerationStats, IndexMode, KvPair, KvSnapshot, KvStore, KvStoreInspector, KvStoreOptions,
+    KvsEngine, LogFormat, SledKvsEngine, SyncPolicy, WriteBatch,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use metrics::{Histogram, HistogramBucket};
+pub use server::{KvsServer, Protocol, ServerStats, ShutdownHandle};
+pub use tls::{ClientTls, ServerTls};
 
 mod client;
 mod common;
 mod engines;
 mod error;
+mod metrics;
 mod server;
 pub mod thread_pool;
+mod tls;
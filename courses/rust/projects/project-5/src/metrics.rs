@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the buckets of histograms in microseconds, from 50us to 10s.
+const BUCKETS: [u64; 16] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 10_000_000,
+];
+
+/// A histogram of durations, like the latencies of requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// The numbers of durations within the upper bounds of the buckets, in
+    /// ascending order of the bounds. A duration is counted in every bucket it's
+    /// within, like in Prometheus.
+    pub buckets: Vec<HistogramBucket>,
+    /// The number of durations, including the ones above all buckets.
+    pub count: u64,
+    /// The sum of the durations in microseconds.
+    pub sum_micros: u64,
+}
+
+/// A bucket of a `Histogram`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    /// The upper bound of the bucket in microseconds, inclusive.
+    pub le_micros: u64,
+    /// The number of durations within the bound.
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Returns the mean of the durations, or `None` if there are none.
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(Duration::from_micros(self.sum_micros / count)),
+        }
+    }
+
+    /// Returns the upper bound of the bucket the `q` quantile is in, like 0.99 for
+    /// the 99th percentile.
+    ///
+    /// Returns `None` if there are no durations or the quantile is above all buckets.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        self.buckets
+            .iter()
+            .find(|bucket| bucket.count >= rank)
+            .map(|bucket| Duration::from_micros(bucket.le_micros))
+    }
+}
+
+/// Records durations in a `Histogram` from many threads.
+pub(crate) struct AtomicHistogram {
+    // not cumulative, unlike `Histogram`
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub(crate) fn new() -> AtomicHistogram {
+        AtomicHistogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        if let Some(i) = BUCKETS.iter().position(|&le| micros <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Histogram {
+        let mut count = 0;
+        let buckets = BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&le_micros, bucket)| {
+                count += bucket.load(Ordering::Relaxed);
+                HistogramBucket { le_micros, count }
+            })
+            .collect();
+        Histogram {
+            buckets,
+            // the count is at least the sum of the buckets even if they're loaded
+            // in the middle of a `record`
+            count: self.count.load(Ordering::Relaxed).max(count),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Writes metrics in the Prometheus text format.
+///
+/// Writing to the `String` never fails, so the results of `writeln!` are ignored.
+pub(crate) struct PrometheusWriter {
+    out: String,
+}
+
+impl PrometheusWriter {
+    pub(crate) fn new() -> PrometheusWriter {
+        PrometheusWriter { out: String::new() }
+    }
+
+    /// Writes the header of a metric, which must come before its samples.
+    pub(crate) fn metric(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        self
+    }
+
+    /// Writes a sample, with labels like `op="get"` if they're not empty.
+    pub(crate) fn sample(
+        &mut self,
+        name: &str,
+        labels: &str,
+        value: impl fmt::Display,
+    ) -> &mut Self {
+        let _ = match labels {
+            "" => writeln!(self.out, "{} {}", name, value),
+            labels => writeln!(self.out, "{}{{{}}} {}", name, labels, value),
+        };
+        self
+    }
+
+    /// Writes a metric with a single sample.
+    pub(crate) fn single(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: impl fmt::Display,
+    ) -> &mut Self {
+        self.metric(name, kind, help).sample(name, "", value)
+    }
+
+    /// Writes a histogram in seconds.
+    pub(crate) fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) -> &mut Self {
+        self.metric(name, "histogram", help);
+        let bucket = format!("{}_bucket", name);
+        for b in &histogram.buckets {
+            let le = format!("le=\"{}\"", b.le_micros as f64 / 1e6);
+            self.sample(&bucket, &le, b.count);
+        }
+        self.sample(&bucket, "le=\"+Inf\"", histogram.count)
+            .sample(
+                &format!("{}_sum", name),
+                "",
+                histogram.sum_micros as f64 / 1e6,
+            )
+            .sample(&format!("{}_count", name), "", histogram.count)
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.out
+    }
+}
@@ -1,64 +1,531 @@
-use crate::common::{Request, Response};
-use crate::{KvsEngine, KvsError, Result};
+use crate::common::{Frame, Request, Response};
+use crate::{KvsEngine, KvsError, Result, ServerTls};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use resp::ScanCursors;
+use serde::Deserialize;
+use stats::{Op, Stats};
+use std::fmt;
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+
+mod http;
+mod resp;
+mod stats;
+
+pub use stats::ServerStats;
+
+/// The maximum number of requests handled at a time on a connection of
+/// `KvsClient`. More requests aren't read until one is answered.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// How long a client may take to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection may take to be finished after the server is shut down,
+/// e.g. to write the responses to a client which doesn't read them.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The protocol a `KvsServer` speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`, which carries every `KvsEngine` operation.
+    Kvs,
+    /// The Redis serialization protocol, so Redis clients can access the server.
+    ///
+    /// `GET`, `SET` with `EX` or `PX`, `DEL`, `EXISTS`, `SCAN`, `PING`, `AUTH` and
+    /// `QUIT` are supported.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Protocol, String> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("invalid protocol: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+        }
+    }
+}
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+    tls: Option<ServerTls>,
+    auth_token: Option<Arc<str>>,
+    backup_root: Option<PathBuf>,
+    shutdown: CancellationToken,
+}
+
+/// A handle to shut down a `KvsServer` gracefully.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: CancellationToken,
+}
+
+impl ShutdownHandle {
+    /// Makes the server stop accepting connections and reading requests.
+    ///
+    /// `KvsServer::run` returns after the requests already read are answered and
+    /// the engine is flushed. Connections which aren't finished 5 seconds after the
+    /// shutdown, e.g. because the client doesn't read its responses, are closed.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
-    }
-
-    /// Run the server listening on the given address
-    pub fn run(self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(&addr)?;
-        let server = listener
-            .incoming()
-            .map_err(|e| error!("IO error: {}", e))
-            .for_each(move |tcp| {
-                let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
+        KvsServer {
+            engine,
+            protocol: Protocol::Kvs,
+            http_addr: None,
+            tls: None,
+            auth_token: None,
+            backup_root: None,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Speaks the given protocol instead of the protocol of `KvsClient`.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Also serves the HTTP API on the given address.
+    ///
+    /// The API has `GET`, `PUT` and `DELETE` on `/kv/{key}`, `/health`, `/stats` and
+    /// `/metrics` for Prometheus. It's served with the same TLS settings and token as
+    /// the main address, with the token sent as `Authorization: Bearer {token}`.
+    pub fn http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Serves TLS connections with the given certificate instead of plain TCP.
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Requires every connection to authenticate with the given token before
+    /// its first request is processed.
+    ///
+    /// A connection which sends another request first or a wrong token gets an
+    /// error response and is closed. With `Protocol::Resp`, the token is sent with
+    /// `AUTH` and the connection is kept open as Redis does.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Lets clients back up the store to directories under the given one.
+    ///
+    /// The directory of a backup request must be a relative path, which is resolved
+    /// against `dir`. Backup requests are refused if it's not set, because they
+    /// write files on the server host.
+    pub fn backup_root(mut self, dir: PathBuf) -> Self {
+        self.backup_root = Some(dir);
+        self
+    }
+
+    /// Returns a handle to shut down the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Run the server listening on the given address until it's shut down.
+    ///
+    /// Every connection is served in its own task on the current tokio runtime.
+    pub async fn run(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        // every listener and connection holds a sender, so the channel is closed
+        // when all are done
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        let shared = Shared {
+            engine: self.engine.clone(),
+            auth_token: self.auth_token.clone(),
+            backup_root: self.backup_root.clone().map(Arc::from),
+            shutdown: self.shutdown.clone(),
+            cursors: Arc::new(ScanCursors::new()),
+            stats: Arc::new(Stats::new()),
+        };
+        if let Some(http_addr) = self.http_addr {
+            let listener = TcpListener::bind(http_addr).await?;
+            let accepting = accept(
+                listener,
+                Frontend::Http,
+                shared.clone(),
+                self.tls.clone(),
+                done_tx.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = accepting.await {
+                    error!("Error on accepting HTTP clients: {}", e);
+                }
             });
-        tokio::run(server);
+        }
+        let res = accept(
+            listener,
+            Frontend::Protocol(self.protocol),
+            shared,
+            self.tls.clone(),
+            done_tx,
+        )
+        .await;
+
+        info!("Shutting down");
+        // the other listener stops on errors of this one too
+        self.shutdown.cancel();
+        done_rx.recv().await;
+        res?;
+        self.engine.flush().await?;
+        info!("Server stopped");
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
-    let (read_half, write_half) = tcp.split();
-    let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-    let resp_stream = read_json
-        .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
-                    }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
+/// What the tasks serving the connections share.
+#[derive(Clone)]
+struct Shared<E> {
+    engine: E,
+    auth_token: Option<Arc<str>>,
+    backup_root: Option<Arc<Path>>,
+    shutdown: CancellationToken,
+    // the scans in progress of `Protocol::Resp`
+    cursors: Arc<ScanCursors>,
+    stats: Arc<Stats>,
+}
+
+impl<E: KvsEngine> Shared<E> {
+    /// Returns the statistics of the server and the engine.
+    async fn stats(&self) -> Result<ServerStats> {
+        let engine = self.engine.stats().await?;
+        Ok(self.stats.snapshot(engine))
+    }
+}
+
+/// What a listener serves.
+#[derive(Clone, Copy)]
+enum Frontend {
+    Protocol(Protocol),
+    Http,
+}
+
+/// Accepts connections and serves each in its own task until the server is shut
+/// down.
+///
+/// `done` is dropped after the listener and every connection task holds a clone
+/// until the connection is closed.
+async fn accept<E: KvsEngine>(
+    listener: TcpListener,
+    frontend: Frontend,
+    shared: Shared<E>,
+    tls: Option<ServerTls>,
+    done: mpsc::Sender<()>,
+) -> Result<()> {
+    loop {
+        let tcp = tokio::select! {
+            res = listener.accept() => res?.0,
+            _ = shared.shutdown.cancelled() => return Ok(()),
+        };
+        let shared = shared.clone();
+        let tls = tls.clone();
+        let done = done.clone();
+        tokio::spawn(async move {
+            let _connection = shared.stats.connection();
+            let drained = drain_deadline(shared.shutdown.clone());
+            let serving = async move {
+                match tls {
+                    Some(tls) => {
+                        let handshake = tls.acceptor.accept(tcp);
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                            Ok(Ok(stream)) => serve(frontend, shared, stream).await,
+                            Ok(Err(e)) => Err(e.into()),
+                            Err(_) => {
+                                Err(KvsError::StringError("TLS handshake timed out".to_owned()))
+                            }
+                        }
                     }
+                    None => serve(frontend, shared, tcp).await,
                 }
+            };
+            let res = tokio::select! {
+                res = serving => res,
+                _ = drained => Err(KvsError::StringError(
+                    "Connection closed after the shutdown timeout".to_owned(),
+                )),
+            };
+            if let Err(e) = res {
+                error!("Error on serving client: {}", e);
+            }
+            drop(done);
+        });
+    }
+}
+
+/// Completes `DRAIN_TIMEOUT` after the server is shut down.
+async fn drain_deadline(shutdown: CancellationToken) {
+    shutdown.cancelled().await;
+    tokio::time::sleep(DRAIN_TIMEOUT).await;
+}
+
+/// Serves the requests on a connection until the client closes it or the server
+/// is shut down.
+async fn serve<E, S>(frontend: Frontend, shared: Shared<E>, stream: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match frontend {
+        Frontend::Protocol(Protocol::Kvs) => serve_kvs(shared, stream).await,
+        Frontend::Protocol(Protocol::Resp) => resp::serve(shared, stream).await,
+        Frontend::Http => http::serve(shared, stream).await,
+    }
+}
+
+/// Serves the requests of `KvsClient`.
+///
+/// Requests are handled concurrently, each in its own task, and every response is
+/// written as soon as it's ready, tagged with the ID of its request. At most
+/// `MAX_IN_FLIGHT` requests are handled at a time, so a client which sends
+/// requests faster than they are answered is slowed down.
+async fn serve_kvs<E, S>(shared: Shared<E>, stream: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_half, write_half) = tokio::io::split(stream);
+    let (tx, rx) = mpsc::channel(MAX_IN_FLIGHT);
+    // The writer stops after the responses of all requests are written, or with
+    // the reader if the connection is dropped at the deadline of a shutdown.
+    tokio::try_join!(
+        read_requests(shared, read_half, tx),
+        write_responses(write_half, rx)
+    )?;
+    Ok(())
+}
+
+/// Reads the requests and handles each in its own task, which sends the response
+/// to `tx`.
+async fn read_requests<E, S>(
+    shared: Shared<E>,
+    read_half: ReadHalf<S>,
+    tx: mpsc::Sender<Frame<Response>>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead,
+{
+    let mut authenticated = shared.auth_token.is_none();
+    let mut requests = FramedRead::new(read_half, LengthDelimitedCodec::new());
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+    loop {
+        let permit = tokio::select! {
+            permit = Arc::clone(&in_flight).acquire_owned() => {
+                permit.expect("the semaphore is never closed")
+            }
+            _ = shared.shutdown.cancelled() => break,
+        };
+        let frame = tokio::select! {
+            frame = requests.next() => match frame {
+                Some(frame) => frame,
+                None => break,
             },
-        )
-        .then(|resp| -> Result<Response> {
-            match resp {
-                Ok(resp) => Ok(resp),
-                Err(e) => Ok(Response::Err(format!("{}", e))),
+            _ = shared.shutdown.cancelled() => break,
+        };
+        let frame = frame?;
+        let Frame { id, body } = match serde_json::from_slice::<Frame<Request>>(&frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                // Only the request fails if its ID can be read, so the other requests
+                // on the connection go on.
+                let id = match serde_json::from_slice::<FrameId>(&frame) {
+                    Ok(FrameId { id }) => id,
+                    Err(_) => return Err(e.into()),
+                };
+                shared.stats.record(Op::Other, false, Duration::default());
+                let body = Response::Err(format!("Invalid request: {}", e));
+                let _ = tx.send(Frame { id, body }).await;
+                continue;
+            }
+        };
+        let req = match body {
+            Request::Auth { token } => {
+                authenticated = check_token(shared.auth_token.as_deref(), &token);
+                let body = if authenticated {
+                    Response::Auth
+                } else {
+                    Response::Err("Invalid auth token".to_owned())
+                };
+                let _ = tx.send(Frame { id, body }).await;
+                if authenticated {
+                    continue;
+                }
+                break;
+            }
+            _ if !authenticated => {
+                let body = Response::Err("Authentication required".to_owned());
+                let _ = tx.send(Frame { id, body }).await;
+                break;
             }
+            req => req,
+        };
+        let shared = shared.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let op = op(&req);
+            let started = Instant::now();
+            let res = handle(&shared, req).await;
+            shared.stats.record(op, res.is_ok(), started.elapsed());
+            let body = match res {
+                Ok(resp) => resp,
+                Err(KvsError::ConditionFailed) => Response::ConditionFailed,
+                Err(e) => Response::Err(format!("{}", e)),
+            };
+            // the writer only stops early if the connection is broken
+            let _ = tx.send(Frame { id, body }).await;
+            drop(permit);
         });
-    let write_json = WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-    write_json
-        .sink_map_err(KvsError::from)
-        .send_all(resp_stream)
-        .map(|_| ())
+    }
+    Ok(())
+}
+
+/// The ID of a frame whose request can't be parsed.
+#[derive(Deserialize)]
+struct FrameId {
+    id: u64,
+}
+
+async fn write_responses<S: AsyncWrite>(
+    write_half: WriteHalf<S>,
+    mut responses: mpsc::Receiver<Frame<Response>>,
+) -> Result<()> {
+    let mut frames = FramedWrite::new(write_half, LengthDelimitedCodec::new());
+    while let Some(frame) = responses.recv().await {
+        let frame = Bytes::from(serde_json::to_vec(&frame)?);
+        // responses which are ready together are flushed together
+        if responses.is_empty() {
+            frames.send(frame).await?;
+        } else {
+            frames.feed(frame).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns how a request is counted in the stats.
+fn op(req: &Request) -> Op {
+    match req {
+        Request::Get { .. } => Op::Get,
+        Request::Set { .. } => Op::Set,
+        Request::Remove { .. } => Op::Remove,
+        Request::Scan { .. } | Request::ScanPrefix { .. } => Op::Scan,
+        _ => Op::Other,
+    }
+}
+
+async fn handle<E: KvsEngine>(shared: &Shared<E>, req: Request) -> Result<Response> {
+    let engine = &shared.engine;
+    match req {
+        Request::Get { key } => engine.get(key).await.map(Response::Get),
+        Request::Set {
+            key,
+            value,
+            ttl: None,
+        } => engine.set(key, value).await.map(|_| Response::Set),
+        Request::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        } => engine
+            .set_with_ttl(key, value, ttl)
+            .await
+            .map(|_| Response::Set),
+        Request::Remove { key } => engine.remove(key).await.map(|_| Response::Remove),
+        Request::WriteBatch(batch) => engine
+            .write_batch(batch)
+            .await
+            .map(|_| Response::WriteBatch),
+        Request::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .await
+            .map(|_| Response::CompareAndSwap),
+        Request::Scan { start, end, limit } => {
+            engine.scan(start, end, limit).await.map(Response::Scan)
+        }
+        Request::ScanPrefix { prefix, limit } => {
+            engine.scan_prefix(prefix, limit).await.map(Response::Scan)
+        }
+        Request::Backup { dir } => {
+            let dir = backup_dir(shared.backup_root.as_deref(), &dir)?;
+            engine.backup(dir).await.map(|_| Response::Backup)
+        }
+        Request::Stats => shared
+            .stats()
+            .await
+            .map(|stats| Response::Stats(Box::new(stats))),
+        Request::Auth { .. } => unreachable!("authentication is handled by the connection"),
+    }
+}
+
+/// Resolves the directory of a backup request against the backup root.
+fn backup_dir(root: Option<&Path>, dir: &Path) -> Result<PathBuf> {
+    let root = root
+        .ok_or_else(|| KvsError::StringError("Backups are not enabled on the server".to_owned()))?;
+    // `..`, roots and prefixes would escape the backup root
+    let relative = dir.components().next().is_some()
+        && dir
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if relative {
+        Ok(root.join(dir))
+    } else {
+        Err(KvsError::StringError(format!(
+            "The backup directory {:?} must be a relative path under the backup root",
+            dir
+        )))
+    }
+}
+
+/// Checks a token sent by a client in constant time, so the time taken doesn't
+/// tell how much of it is right.
+fn check_token(expected: Option<&str>, token: &str) -> bool {
+    match expected {
+        Some(expected) => {
+            expected.len() == token.len()
+                && expected
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        None => true,
+    }
 }
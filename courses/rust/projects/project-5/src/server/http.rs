@@ -0,0 +1,198 @@
+use super::stats::Op;
+use super::{check_token, Shared};
+use crate::{KvsEngine, KvsError, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The maximum length of a value in a `PUT` request.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+type Body = Full<Bytes>;
+
+/// Serves the HTTP API on a connection until the client closes it or the server
+/// is shut down.
+///
+/// A shutdown lets the request in progress finish before the connection is closed.
+pub(super) async fn serve<E, S>(shared: Shared<E>, stream: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let service = service_fn(|req| {
+        let shared = shared.clone();
+        async move { Ok::<_, Infallible>(handle(&shared, req).await) }
+    });
+    let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = shared.shutdown.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    res.map_err(|e| KvsError::StringError(format!("HTTP error: {}", e)))
+}
+
+/// Routes a request:
+///
+/// - `GET /health` returns `{"status": "ok"}`, without authentication.
+/// - `GET /stats` returns the `ServerStats` in JSON.
+/// - `GET /metrics` returns the `ServerStats` in the Prometheus text format.
+/// - `GET /kv/{key}` returns the value of the key.
+/// - `PUT /kv/{key}` sets the value of the key to the body. The key expires after
+///   `ttl` seconds if the `ttl` query parameter is given.
+/// - `DELETE /kv/{key}` removes the key.
+///
+/// Keys in paths are percent-decoded. The token of the server is sent as
+/// `Authorization: Bearer {token}`. Errors are JSON like `{"error": "Key not found"}`.
+async fn handle<E: KvsEngine>(shared: &Shared<E>, req: Request<Incoming>) -> Response<Body> {
+    let path = req.uri().path().to_owned();
+    if path == "/health" {
+        return match *req.method() {
+            Method::GET => json(StatusCode::OK, json!({ "status": "ok" })),
+            _ => method_not_allowed("GET"),
+        };
+    }
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !check_token(shared.auth_token.as_deref(), token) {
+        let mut resp = error(StatusCode::UNAUTHORIZED, "Authentication required");
+        resp.headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return resp;
+    }
+
+    if path == "/stats" || path == "/metrics" {
+        if req.method() != Method::GET {
+            return method_not_allowed("GET");
+        }
+        return match shared.stats().await {
+            Ok(stats) if path == "/stats" => json(StatusCode::OK, json!(stats)),
+            Ok(stats) => Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(stats.to_prometheus()))
+                .unwrap(),
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        };
+    }
+    match path.strip_prefix("/kv/") {
+        Some(key) => {
+            let key = percent_decode_str(key).collect();
+            handle_kv(shared, key, req).await
+        }
+        None => error(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+async fn handle_kv<E: KvsEngine>(
+    shared: &Shared<E>,
+    key: Vec<u8>,
+    req: Request<Incoming>,
+) -> Response<Body> {
+    let started = Instant::now();
+    let (op, res) = match *req.method() {
+        Method::GET => {
+            let res = shared.engine.get(key).await.map(|value| match value {
+                Some(value) => Response::builder()
+                    .header(header::CONTENT_TYPE, "application/octet-stream")
+                    .body(Body::from(value))
+                    .unwrap(),
+                None => error(StatusCode::NOT_FOUND, "Key not found"),
+            });
+            (Op::Get, res)
+        }
+        Method::PUT => (Op::Set, put(&shared.engine, key, req).await),
+        Method::DELETE => {
+            let res = shared.engine.remove(key).await.map(|()| no_content());
+            (Op::Remove, res)
+        }
+        _ => return method_not_allowed("GET, PUT, DELETE"),
+    };
+    shared.stats.record(op, res.is_ok(), started.elapsed());
+    res.unwrap_or_else(|e| match e {
+        KvsError::KeyNotFound => error(StatusCode::NOT_FOUND, "Key not found"),
+        e => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    })
+}
+
+async fn put<E: KvsEngine>(
+    engine: &E,
+    key: Vec<u8>,
+    req: Request<Incoming>,
+) -> Result<Response<Body>> {
+    let ttl = match ttl_param(req.uri().query()) {
+        Ok(ttl) => ttl,
+        Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg)),
+    };
+    let value = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return Ok(error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The value is too large",
+            ))
+        }
+        Err(e) => return Err(KvsError::StringError(format!("HTTP error: {}", e))),
+    };
+    match ttl {
+        Some(ttl) => engine.set_with_ttl(key, value, ttl).await?,
+        None => engine.set(key, value).await?,
+    }
+    Ok(no_content())
+}
+
+/// Parses the `ttl` query parameter in seconds.
+fn ttl_param(query: Option<&str>) -> std::result::Result<Option<Duration>, &'static str> {
+    let ttl = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| param.strip_prefix("ttl="));
+    match ttl.map(str::parse::<u64>) {
+        Some(Ok(secs)) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+        Some(_) => Err("Invalid ttl"),
+        None => Ok(None),
+    }
+}
+
+fn json(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Body> {
+    json(status, json!({ "error": msg }))
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::default())
+        .unwrap()
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut resp = error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    resp.headers_mut()
+        .insert(header::ALLOW, HeaderValue::from_static(allow));
+    resp
+}
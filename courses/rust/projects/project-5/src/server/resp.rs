@@ -0,0 +1,555 @@
+use super::stats::Op;
+use super::{check_token, Shared};
+use crate::{KvsEngine, KvsError, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use lru::LruCache;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// The maximum length of a line of a command, as in Redis.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// The maximum number of arguments of a command.
+const MAX_ARGS: i64 = 1024 * 1024;
+/// The maximum length of an argument of a command, as in Redis.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// The maximum number of scans in progress which can be continued.
+const MAX_CURSORS: usize = 1024;
+/// The number of keys `SCAN` returns at most without `COUNT`, as in Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serves the commands of a Redis client in order until it quits or the server
+/// is shut down.
+pub(super) async fn serve<E, S>(shared: Shared<E>, stream: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frames = Framed::new(stream, RespCodec::default());
+    let mut authenticated = shared.auth_token.is_none();
+    loop {
+        let args = tokio::select! {
+            frame = frames.next() => match frame {
+                Some(Ok(args)) => args,
+                Some(Err(e)) => {
+                    // the rest of the input can't be parsed
+                    frames.send(Reply::Error(format!("ERR {}", e))).await?;
+                    break;
+                }
+                None => break,
+            },
+            _ = shared.shutdown.cancelled() => break,
+        };
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let reply = match name.as_str() {
+            "AUTH" if shared.auth_token.is_none() && (args.len() == 2 || args.len() == 3) => {
+                // as in Redis, so clients can tell the server isn't configured as expected
+                Reply::Error(
+                    "ERR AUTH <password> called without any password configured for the \
+                     default user. Are you sure your configuration is correct?"
+                        .to_owned(),
+                )
+            }
+            "AUTH" if args.len() == 2 || args.len() == 3 => {
+                // the user name of Redis 6 is ignored
+                let token = String::from_utf8_lossy(&args[args.len() - 1]);
+                if check_token(shared.auth_token.as_deref(), &token) {
+                    authenticated = true;
+                    Reply::ok()
+                } else {
+                    Reply::Error("WRONGPASS invalid username-password pair".to_owned())
+                }
+            }
+            "QUIT" => {
+                frames.send(Reply::ok()).await?;
+                break;
+            }
+            _ if !authenticated => Reply::Error("NOAUTH Authentication required.".to_owned()),
+            _ => {
+                let started = Instant::now();
+                let reply = execute(&shared, &name, args)
+                    .await
+                    .unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)));
+                let op = match name.as_str() {
+                    "GET" => Op::Get,
+                    "SET" => Op::Set,
+                    "DEL" => Op::Remove,
+                    "SCAN" => Op::Scan,
+                    _ => Op::Other,
+                };
+                let ok = !matches!(reply, Reply::Error(_));
+                shared.stats.record(op, ok, started.elapsed());
+                reply
+            }
+        };
+        frames.send(reply).await?;
+    }
+    Ok(())
+}
+
+/// Executes a command on the engine.
+///
+/// Invalid commands get error replies, while errors of the engine are returned.
+async fn execute<E: KvsEngine>(
+    shared: &Shared<E>,
+    name: &str,
+    mut args: Vec<Vec<u8>>,
+) -> Result<Reply> {
+    let engine = &shared.engine;
+    let reply = match (name, args.len()) {
+        ("PING", 1) => Reply::Status("PONG".to_owned()),
+        ("PING", 2) => Reply::Bulk(args.pop()),
+        ("GET", 2) => Reply::Bulk(engine.get(args.swap_remove(1)).await?),
+        ("SET", n) if n >= 3 => set(engine, args).await?,
+        ("DEL", n) if n >= 2 => {
+            let mut removed = 0;
+            for key in args.drain(1..) {
+                match engine.remove(key).await {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        ("EXISTS", n) if n >= 2 => {
+            let mut found = 0;
+            for key in args.drain(1..) {
+                if engine.get(key).await?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        ("SCAN", n) if n >= 2 => scan(shared, args).await?,
+        ("PING", _)
+        | ("GET", _)
+        | ("SET", _)
+        | ("DEL", _)
+        | ("EXISTS", _)
+        | ("SCAN", _)
+        | ("AUTH", _) => Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )),
+        _ => Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&args[0])
+        )),
+    };
+    Ok(reply)
+}
+
+/// `SET key value [EX seconds | PX milliseconds]`
+async fn set<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> Result<Reply> {
+    let mut args = args.into_iter().skip(1);
+    let (key, value) = (args.next().unwrap(), args.next().unwrap());
+    let mut ttl = None;
+    while let Some(option) = args.next() {
+        let unit = match option.to_ascii_uppercase().as_slice() {
+            b"EX" if ttl.is_none() => 1000,
+            b"PX" if ttl.is_none() => 1,
+            _ => return Ok(Reply::syntax_error()),
+        };
+        let time = match args.next() {
+            Some(time) => parse::<u64>(&time),
+            None => return Ok(Reply::syntax_error()),
+        };
+        ttl = match time {
+            Some(time) if time > 0 => Some(Duration::from_millis(time.saturating_mul(unit))),
+            _ => {
+                let msg = "ERR invalid expire time in 'set' command";
+                return Ok(Reply::Error(msg.to_owned()));
+            }
+        };
+    }
+    match ttl {
+        Some(ttl) => engine.set_with_ttl(key, value, ttl).await?,
+        None => engine.set(key, value).await?,
+    }
+    Ok(Reply::ok())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// Unlike Redis, `COUNT` limits the keys looked at rather than hinting at it, and
+/// a cursor can be continued on any connection until it's forgotten.
+async fn scan<E: KvsEngine>(shared: &Shared<E>, args: Vec<Vec<u8>>) -> Result<Reply> {
+    let mut args = args.into_iter().skip(1);
+    let start = match parse::<u64>(&args.next().unwrap()) {
+        Some(0) => Vec::new(),
+        Some(cursor) => match shared.cursors.get(cursor) {
+            Some(start) => start,
+            None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        },
+        None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let Some(option) = args.next() {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => return Ok(Reply::syntax_error()),
+        };
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(arg),
+            b"COUNT" => match parse::<usize>(&arg) {
+                Some(n) if n > 0 => count = n,
+                _ => return Ok(Reply::syntax_error()),
+            },
+            _ => return Ok(Reply::syntax_error()),
+        }
+    }
+
+    // only the keys starting with the literal prefix of the pattern can match
+    let prefix = pattern.as_deref().map(literal_prefix).unwrap_or_default();
+    let start = start.max(prefix.to_vec());
+    let keys = shared
+        .engine
+        .scan_keys(start, prefix_end(prefix), Some(count))
+        .await?;
+    let cursor = match keys.last() {
+        Some(last) if keys.len() == count => {
+            let mut next = last.clone();
+            next.push(0);
+            shared.cursors.insert(next)
+        }
+        _ => 0,
+    };
+    let keys = keys
+        .into_iter()
+        .filter(|key| match &pattern {
+            Some(pattern) => glob_match(pattern, key),
+            None => true,
+        })
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(cursor.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn parse<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// The start keys of the next pages of the scans in progress, by their cursors.
+///
+/// Redis clients expect a cursor to be a number, while a scan of the engine goes
+/// on from a key, so the server remembers the key of every cursor it returns. The
+/// least recently used cursors are forgotten if there are too many.
+pub(super) struct ScanCursors {
+    next: AtomicU64,
+    starts: Mutex<LruCache<u64, Vec<u8>>>,
+}
+
+impl ScanCursors {
+    pub(super) fn new() -> ScanCursors {
+        ScanCursors {
+            // 0 starts and ends a scan
+            next: AtomicU64::new(1),
+            starts: Mutex::new(LruCache::new(MAX_CURSORS)),
+        }
+    }
+
+    fn insert(&self, start: Vec<u8>) -> u64 {
+        let cursor = self.next.fetch_add(1, Ordering::Relaxed);
+        self.starts.lock().unwrap().put(cursor, start);
+        cursor
+    }
+
+    fn get(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.starts.lock().unwrap().get(&cursor).cloned()
+    }
+}
+
+/// Returns the part of a pattern before its first special character.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let len = pattern
+        .iter()
+        .position(|c| b"*?[\\".contains(c))
+        .unwrap_or(pattern.len());
+    &pattern[..len]
+}
+
+/// Returns the smallest key greater than all keys starting with `prefix`, or
+/// `None` if there's no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Matches a key against a glob-style pattern of Redis, which supports `*`, `?`,
+/// classes like `[a-z]` or `[^abc]`, and `\` to escape a special character.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // the positions after the last `*` and the key it has matched up to, to
+    // backtrack to if the rest doesn't match
+    let mut star = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                p += 1;
+                star = Some((p, k));
+                continue;
+            }
+            if let Some(len) = match_one(&pattern[p..], key[k]) {
+                p += len;
+                k += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches a byte against the first element of a pattern, which is not `*`.
+///
+/// Returns the length of the element if it matches.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    let (matched, len) = match pattern[0] {
+        b'?' => (true, 1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c, 2),
+        b'[' => {
+            let negated = pattern.get(1) == Some(&b'^');
+            let mut i = if negated { 2 } else { 1 };
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (low, high) = (
+                        pattern[i].min(pattern[i + 2]),
+                        pattern[i].max(pattern[i + 2]),
+                    );
+                    matched |= low <= c && c <= high;
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // an unclosed class ends with the pattern, as in Redis
+            (matched != negated, (i + 1).min(pattern.len()))
+        }
+        literal => (literal == c, 1),
+    };
+    if matched {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// A reply of RESP.
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Status("OK".to_owned())
+    }
+
+    fn syntax_error() -> Reply {
+        Reply::Error("ERR syntax error".to_owned())
+    }
+
+    fn write(&self, dst: &mut BytesMut) {
+        match self {
+            Reply::Status(status) => {
+                dst.put_u8(b'+');
+                dst.put_slice(single_line(status).as_bytes());
+            }
+            Reply::Error(msg) => {
+                dst.put_u8(b'-');
+                dst.put_slice(single_line(msg).as_bytes());
+            }
+            Reply::Integer(n) => dst.put_slice(format!(":{}", n).as_bytes()),
+            Reply::Bulk(None) => dst.put_slice(b"$-1"),
+            Reply::Bulk(Some(bytes)) => {
+                dst.put_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                dst.put_slice(bytes);
+            }
+            Reply::Array(replies) => {
+                dst.put_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.write(dst);
+                }
+                return;
+            }
+        }
+        dst.put_slice(b"\r\n");
+    }
+}
+
+/// Replaces line breaks, which would end a status or an error early.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+/// Decodes commands and encodes replies of RESP.
+///
+/// A command is an array of bulk strings, as Redis clients send, or an inline
+/// command of words separated by spaces, as typed in telnet.
+#[derive(Default)]
+struct RespCodec {
+    // the array being parsed, kept while its input is incomplete so the
+    // arguments already parsed aren't parsed again on every read
+    array: Option<PartialArray>,
+}
+
+/// An array of bulk strings with some of its arguments parsed.
+struct PartialArray {
+    count: usize,
+    args: Vec<Vec<u8>>,
+    // the position of the next argument in the input
+    pos: usize,
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>> {
+        loop {
+            let parsed = if let Some(array) = &mut self.array {
+                parse_array(src, array)?
+            } else if src.first() == Some(&b'*') {
+                match parse_array_header(src)? {
+                    Some(array) => {
+                        self.array = Some(array);
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            } else {
+                parse_inline(src)?
+            };
+            match parsed {
+                Some((args, len)) => {
+                    self.array = None;
+                    src.advance(len);
+                    // empty commands are skipped
+                    if !args.is_empty() {
+                        return Ok(Some(args));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Encoder<Reply> for RespCodec {
+    type Error = KvsError;
+
+    fn encode(&mut self, reply: Reply, dst: &mut BytesMut) -> Result<()> {
+        reply.write(dst);
+        Ok(())
+    }
+}
+
+/// A parsed command and the length of its input.
+type Parsed = Option<(Vec<Vec<u8>>, usize)>;
+
+fn parse_array_header(src: &[u8]) -> Result<Option<PartialArray>> {
+    let (header, pos) = match line(src, 0)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match parse::<i64>(&header[1..]) {
+        Some(count) if count <= MAX_ARGS => count.max(0) as usize,
+        _ => return Err(protocol_error("invalid multibulk length")),
+    };
+    Ok(Some(PartialArray {
+        count,
+        args: Vec::new(),
+        pos,
+    }))
+}
+
+/// Parses the arguments of `array` which are complete in `src`, and returns the
+/// command once all of them are.
+fn parse_array(src: &[u8], array: &mut PartialArray) -> Result<Parsed> {
+    while array.args.len() < array.count {
+        let (header, start) = match line(src, array.pos)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error(&format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header[..header.len().min(1)])
+            )));
+        }
+        let len = match parse::<i64>(&header[1..]) {
+            Some(len) if (0..=MAX_BULK_LEN).contains(&len) => len as usize,
+            _ => return Err(protocol_error("invalid bulk length")),
+        };
+        let end = start + len;
+        if src.len() < end + 2 {
+            return Ok(None);
+        }
+        if &src[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("expected '\\r\\n' after bulk string"));
+        }
+        array.args.push(src[start..end].to_vec());
+        array.pos = end + 2;
+    }
+    Ok(Some((std::mem::take(&mut array.args), array.pos)))
+}
+
+fn parse_inline(src: &[u8]) -> Result<Parsed> {
+    Ok(line(src, 0)?.map(|(line, len)| {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        (args, len)
+    }))
+}
+
+/// Returns the line starting at `pos` without the line break, and the position
+/// of the next line.
+fn line(src: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>> {
+    match src[pos..].iter().position(|&c| c == b'\n') {
+        Some(len) => {
+            let line = &src[pos..pos + len];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            Ok(Some((line, pos + len + 1)))
+        }
+        None if src.len() - pos > MAX_LINE_LEN => Err(protocol_error("too big request line")),
+        None => Ok(None),
+    }
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", msg))
+}
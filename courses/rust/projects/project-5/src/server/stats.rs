@@ -0,0 +1,262 @@
+use crate::metrics::{AtomicHistogram, Histogram, PrometheusWriter};
+use crate::EngineStats;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The statistics of a running `KvsServer`, over all its protocols.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// The number of seconds since the server started.
+    pub uptime_secs: u64,
+    /// The number of connections accepted.
+    pub connections: u64,
+    /// The number of connections open now.
+    pub active_connections: u64,
+    /// The number of requests served, including failed ones.
+    pub requests: u64,
+    /// The number of requests getting a value.
+    pub gets: u64,
+    /// The number of requests setting values.
+    pub sets: u64,
+    /// The number of requests removing keys.
+    pub removes: u64,
+    /// The number of scans.
+    pub scans: u64,
+    /// The number of requests which failed, like removing a missing key.
+    pub errors: u64,
+    /// How long the requests took to handle.
+    pub latency: Histogram,
+    /// The statistics of the storage engine.
+    pub engine: EngineStats,
+}
+
+impl ServerStats {
+    /// Formats the statistics in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut w = PrometheusWriter::new();
+        // the counters are loaded one by one, so the sum may be more than the total
+        let other = self
+            .requests
+            .saturating_sub(self.gets + self.sets + self.removes + self.scans);
+        w.single(
+            "kvs_uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+            self.uptime_secs,
+        )
+        .single(
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted.",
+            self.connections,
+        )
+        .single(
+            "kvs_active_connections",
+            "gauge",
+            "Connections open now.",
+            self.active_connections,
+        )
+        .metric("kvs_requests_total", "counter", "Requests served.")
+        .sample("kvs_requests_total", "op=\"get\"", self.gets)
+        .sample("kvs_requests_total", "op=\"set\"", self.sets)
+        .sample("kvs_requests_total", "op=\"remove\"", self.removes)
+        .sample("kvs_requests_total", "op=\"scan\"", self.scans)
+        .sample("kvs_requests_total", "op=\"other\"", other)
+        .single(
+            "kvs_request_errors_total",
+            "counter",
+            "Requests which failed.",
+            self.errors,
+        )
+        .histogram(
+            "kvs_request_duration_seconds",
+            "How long requests took to handle.",
+            &self.latency,
+        );
+
+        let engine = &self.engine;
+        let pool = &engine.thread_pool;
+        w.single(
+            "kvs_disk_usage_bytes",
+            "gauge",
+            "Bytes the data takes on the disk.",
+            engine.disk_usage,
+        )
+        .single(
+            "kvs_thread_pool_threads",
+            "gauge",
+            "Threads of the engine thread pool.",
+            pool.threads,
+        )
+        .single(
+            "kvs_thread_pool_queued_jobs",
+            "gauge",
+            "Jobs waiting for a thread.",
+            pool.queued,
+        )
+        .single(
+            "kvs_thread_pool_running_jobs",
+            "gauge",
+            "Jobs running now.",
+            pool.running,
+        )
+        .single(
+            "kvs_thread_pool_completed_jobs_total",
+            "counter",
+            "Jobs finished.",
+            pool.completed,
+        )
+        .histogram(
+            "kvs_thread_pool_wait_seconds",
+            "How long jobs waited for a thread.",
+            &pool.wait,
+        );
+        if let Some(compaction) = &engine.compaction {
+            w.single(
+                "kvs_compaction_running",
+                "gauge",
+                "Whether a compaction is running.",
+                compaction.running as u8,
+            )
+            .single(
+                "kvs_compactions_failed_total",
+                "counter",
+                "Compactions failed.",
+                compaction.failed,
+            )
+            .single(
+                "kvs_uncompacted_bytes",
+                "gauge",
+                "Bytes of stale commands a compaction could save.",
+                compaction.uncompacted,
+            )
+            .histogram(
+                "kvs_compaction_duration_seconds",
+                "How long finished compactions took.",
+                &compaction.durations,
+            );
+        }
+        if let Some(cache) = &engine.cache {
+            w.metric(
+                "kvs_cache_reads_total",
+                "counter",
+                "Reads of the value cache.",
+            )
+            .sample("kvs_cache_reads_total", "result=\"hit\"", cache.hits)
+            .sample("kvs_cache_reads_total", "result=\"miss\"", cache.misses)
+            .single(
+                "kvs_cache_size_bytes",
+                "gauge",
+                "Bytes of the cached values.",
+                cache.size,
+            )
+            .single(
+                "kvs_cache_capacity_bytes",
+                "gauge",
+                "Maximum bytes of the cached values.",
+                cache.capacity,
+            );
+        }
+        w.finish()
+    }
+}
+
+/// The kinds of requests counted separately.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Op {
+    Get,
+    Set,
+    Remove,
+    Scan,
+    Other,
+}
+
+/// The counters of a server.
+pub(super) struct Stats {
+    started: Instant,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    requests: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    scans: AtomicU64,
+    errors: AtomicU64,
+    latency: AtomicHistogram,
+}
+
+impl Stats {
+    pub(super) fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            sets: AtomicU64::new(0),
+            removes: AtomicU64::new(0),
+            scans: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: AtomicHistogram::new(),
+        }
+    }
+
+    /// Counts a new connection, which is open until the returned guard is dropped.
+    pub(super) fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            stats: Arc::clone(self),
+        }
+    }
+
+    /// Counts a served request which took `elapsed` to handle.
+    pub(super) fn record(&self, op: Op, ok: bool, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.latency.record(elapsed);
+        let counter = match op {
+            Op::Get => Some(&self.gets),
+            Op::Set => Some(&self.sets),
+            Op::Remove => Some(&self.removes),
+            Op::Scan => Some(&self.scans),
+            Op::Other => None,
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn snapshot(&self, engine: EngineStats) -> ServerStats {
+        ServerStats {
+            uptime_secs: self.started.elapsed().as_secs(),
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            scans: self.scans.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            engine,
+        }
+    }
+}
+
+/// Counts a connection as closed when it's dropped.
+pub(super) struct ConnectionGuard {
+    stats: Arc<Stats>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
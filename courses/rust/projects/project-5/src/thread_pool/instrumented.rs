@@ -0,0 +1,92 @@
+use super::ThreadPool;
+use crate::metrics::{AtomicHistogram, Histogram};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The statistics of the jobs of an `InstrumentedThreadPool`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadPoolStats {
+    /// The number of threads the pool was created with.
+    pub threads: u32,
+    /// The number of jobs waiting for a thread.
+    pub queued: u64,
+    /// The number of jobs running now.
+    pub running: u64,
+    /// The number of jobs finished, including panicked ones.
+    pub completed: u64,
+    /// How long jobs waited for a thread.
+    pub wait: Histogram,
+}
+
+/// A thread pool which counts the jobs spawned into another one.
+#[derive(Clone)]
+pub struct InstrumentedThreadPool<P: ThreadPool> {
+    pool: P,
+    counters: Arc<Counters>,
+}
+
+struct Counters {
+    threads: u32,
+    queued: AtomicU64,
+    running: AtomicU64,
+    completed: AtomicU64,
+    wait: AtomicHistogram,
+}
+
+impl<P: ThreadPool> InstrumentedThreadPool<P> {
+    /// Returns the statistics of the jobs spawned so far.
+    pub fn stats(&self) -> ThreadPoolStats {
+        let c = &self.counters;
+        ThreadPoolStats {
+            threads: c.threads,
+            queued: c.queued.load(Ordering::Relaxed),
+            running: c.running.load(Ordering::Relaxed),
+            completed: c.completed.load(Ordering::Relaxed),
+            wait: c.wait.snapshot(),
+        }
+    }
+}
+
+impl<P: ThreadPool> ThreadPool for InstrumentedThreadPool<P> {
+    fn new(threads: u32) -> Result<Self> {
+        Ok(InstrumentedThreadPool {
+            pool: P::new(threads)?,
+            counters: Arc::new(Counters {
+                threads,
+                queued: AtomicU64::new(0),
+                running: AtomicU64::new(0),
+                completed: AtomicU64::new(0),
+                wait: AtomicHistogram::new(),
+            }),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let counters = Arc::clone(&self.counters);
+        let spawned = Instant::now();
+        counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.pool.spawn(move || {
+            counters.wait.record(spawned.elapsed());
+            counters.queued.fetch_sub(1, Ordering::Relaxed);
+            counters.running.fetch_add(1, Ordering::Relaxed);
+            let _running = Running(&counters);
+            job();
+        });
+    }
+}
+
+/// Counts a job as completed when it's dropped, even if the job panics.
+struct Running<'a>(&'a Counters);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
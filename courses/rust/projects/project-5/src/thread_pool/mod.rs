@@ -3,16 +3,18 @@
 
 use crate::Result;
 
+mod instrumented;
 mod naive;
 mod rayon;
 mod shared_queue;
 
+pub use self::instrumented::{InstrumentedThreadPool, ThreadPoolStats};
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
 
 /// The trait that all thread pools should implement.
-pub trait ThreadPool: Clone + Send + 'static {
+pub trait ThreadPool: Clone + Send + Sync + 'static {
     /// Creates a new thread pool, immediately spawning the specified number of
     /// threads.
     ///
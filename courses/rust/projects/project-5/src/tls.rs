@@ -0,0 +1,108 @@
+use crate::{KvsError, Result};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// The certificate a `KvsServer` serves TLS connections with.
+#[derive(Clone)]
+pub struct ServerTls {
+    pub(crate) acceptor: TlsAcceptor,
+}
+
+impl ServerTls {
+    /// Loads the certificate chain and the private key from PEM files.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> Result<ServerTls> {
+        let certs = load_certs(cert)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(ServerTls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+}
+
+impl fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTls").finish()
+    }
+}
+
+/// The certificates a `KvsClient` trusts when it connects to a server with TLS.
+#[derive(Clone)]
+pub struct ClientTls {
+    connector: TlsConnector,
+    domain: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// Trusts the certificates in a PEM file, which are usually of a CA or of a
+    /// self-signed server.
+    pub fn from_ca_file(ca: &Path) -> Result<ClientTls> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(ClientTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            domain: None,
+        })
+    }
+
+    /// Sets the name the certificate of the server must be valid for.
+    ///
+    /// By default the certificate must be valid for the IP address of the server.
+    pub fn domain(mut self, domain: &str) -> Result<ClientTls> {
+        let domain = ServerName::try_from(domain.to_owned())
+            .map_err(|_| KvsError::StringError(format!("Invalid server name {}", domain)))?;
+        self.domain = Some(domain);
+        Ok(self)
+    }
+
+    pub(crate) fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    /// Returns the name to verify the certificate of the server at `addr` with.
+    pub(crate) fn server_name(&self, addr: SocketAddr) -> ServerName<'static> {
+        match &self.domain {
+            Some(domain) => domain.clone(),
+            None => ServerName::from(addr.ip()),
+        }
+    }
+}
+
+impl fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientTls")
+            .field("domain", &self.domain)
+            .finish()
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(KvsError::StringError(format!(
+            "No certificates in {:?}",
+            path
+        )));
+    }
+    Ok(certs)
+}
+
+fn pem_error(path: &Path, err: pem::Error) -> KvsError {
+    KvsError::StringError(format!("Invalid PEM file {:?}: {}", path, err))
+}
@@ -1,5 +1,9 @@
 use assert_cmd::prelude::*;
+use futures::executor::block_on;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
+use rcgen::CertifiedKey;
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -151,6 +155,82 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_invalid_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--protocol", "http"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid protocol: http"));
+}
+
+#[test]
+fn server_cli_invalid_thread_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--thread-pool", "fifo"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid thread pool: fifo"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("The number of threads must be positive"));
+}
+
+// Invalid config files should be rejected before anything is written.
+#[test]
+fn server_cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "addr = \"127.0.0.1:4000\"\nport = 4000\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `port`"));
+
+    fs::write(&config, "thread-pool = \"fifo\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid thread pool: fifo"));
+
+    fs::write(&config, "tls-cert = \"cert.pem\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("The TLS certificate and key must be set together"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -283,6 +363,87 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\nkey2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "ff00", "0a80", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "/wA=", "--encoding", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("CoA=\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "scan",
+            "--prefix",
+            "ff",
+            "--encoding",
+            "hex",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ff00\t0a80\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "zz", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid hex input"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+
+    thread::sleep(Duration::from_millis(1100));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key1", "--addr", addr])
@@ -335,3 +496,609 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_backup_and_restore(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let backup_path = backup_dir.path().join("backup");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--backup-root"])
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..100 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{}", i % 10), &format!("value{}", i)])
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // a backup is never written over existing data
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already contains"));
+
+    // nor outside the backup root
+    for dir in &["../escaped", backup_path.to_str().unwrap()] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["backup", dir, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("must be a relative path"));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key0", "after-backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // Restore to a new directory
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let restore_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--addr",
+            addr,
+            "--restore-from",
+            backup_path.to_str().unwrap(),
+        ])
+        .current_dir(&restore_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", &format!("key{}", i), "--addr", addr])
+            .current_dir(&restore_dir)
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", 90 + i));
+    }
+    // this server has no backup root
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", "backup2", "--addr", addr])
+        .current_dir(&restore_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not enabled"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let content = fs::read_to_string(restore_dir.path().join("engine")).unwrap();
+    assert_eq!(content, engine);
+
+    // Restoring into a directory with data fails
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--restore-from",
+            backup_path.to_str().unwrap(),
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already has data"));
+
+    // even without the engine file
+    fs::remove_file(temp_dir.path().join("engine")).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--restore-from",
+            backup_path.to_str().unwrap(),
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already has data"));
+}
+
+#[test]
+fn cli_backup_and_restore_kvs_engine() {
+    cli_backup_and_restore("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_backup_and_restore_sled_engine() {
+    cli_backup_and_restore("sled", "127.0.0.1:4007");
+}
+
+// `kvs-admin migrate` should move the data to another engine for `kvs-server`.
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{}", i % 10), &format!("value{}", i)])
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // keys with a TTL keep it, unless they have expired
+    for (key, ttl) in &[("expiring", "3600"), ("expired", "1")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, "value", "--ttl", ttl, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(1100));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("The data is of the kvs engine"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Migrated 10 pairs from kvs to sled\n");
+    let content = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(content, "sled");
+    assert!(temp_dir.path().join("kvs.old").join("1.log").exists());
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        // sled must release its lock before the migration opens it
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    for i in 1..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", &format!("key{}", i), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", 10 + i));
+    }
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // and back to kvs
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Migrated 10 pairs from sled to kvs\n");
+    let content = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(content, "kvs");
+    assert!(temp_dir.path().join("sled.old").join("conf").exists());
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).unwrap();
+    let (value, ttl) = block_on(store.get_with_ttl(b"expiring".to_vec()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(value, b"value");
+    let ttl = ttl.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(block_on(store.get(b"expired".to_vec())).unwrap(), None);
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is left by an earlier migration"));
+}
+
+// `kvs-admin` should inspect and compact the kvs data in the current directory.
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).unwrap();
+    for i in 0..30 {
+        block_on(store.set(
+            format!("key{}", i % 10).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        ))
+        .unwrap();
+    }
+    block_on(store.remove(b"key0".to_vec())).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log: Binary"))
+        .stdout(contains("keys: 9\n"));
+
+    let expected: String = (1..10)
+        .map(|i| format!("key{}\tvalue{}\n", i, 20 + i))
+        .collect();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Verified 1 log files\n");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Compacted"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("hint file"))
+        .stdout(contains("keys: 9\n"))
+        .stdout(contains("stale bytes: 0\n"));
+
+    // corrupt the last record of the compaction file, which follows the log file
+    // created when `kvs-admin compact` opens the store
+    let log = temp_dir.path().join("3.log");
+    let mut content = fs::read(&log).unwrap();
+    *content.last_mut().unwrap() ^= 0xff;
+    fs::write(&log, content).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("3.log at offset"))
+        .stderr(contains("Found 1 corrupted records"));
+}
+
+// `kvs-server` should shut down gracefully on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn cli_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Server stopped"));
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).unwrap();
+    assert_eq!(
+        block_on(store.get(b"key1".to_vec())).unwrap(),
+        Some(b"value1".to_vec())
+    );
+}
+
+// `kvs-client` should access a `kvs-server` with TLS and a token only with the
+// CA file and the token.
+#[test]
+fn cli_tls_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names).unwrap();
+    fs::write(temp_dir.path().join("cert.pem"), cert.pem()).unwrap();
+    fs::write(temp_dir.path().join("key.pem"), key_pair.serialize_pem()).unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--auth-token", "secret"])
+        .args(&["--tls-cert", "../cert.pem", "--tls-key", "../key.pem"])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(&["--tls-ca", "cert.pem", "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .args(&["--tls-ca", "cert.pem", "--tls-domain", "localhost"])
+        .env("KVS_AUTH_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--tls-ca", "cert.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client stats` should show the statistics of the server in all formats.
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4022";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "requests: 1 (0 gets, 1 sets, 0 removes, 0 scans, 0 errors)\n",
+        ))
+        .stdout(contains("thread pool: "))
+        .stdout(contains("compaction: 0 finished, 0 failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr, "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"sets\": 1,"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr, "--format", "prometheus"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("kvs_requests_total{op=\"set\"} 1\n"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server` should take its options from a config file, with the arguments
+// overriding them and relative paths relative to the file.
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    fs::create_dir(temp_dir.path().join("conf")).unwrap();
+    fs::write(
+        temp_dir.path().join("conf/kvs.toml"),
+        r#"
+addr = "127.0.0.1:4099"
+engine = "kvs"
+data-dir = "data"
+threads = 2
+thread-pool = "shared-queue"
+compaction-threshold = 4096
+log-level = "debug"
+"#,
+    )
+    .unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "conf/kvs.toml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Thread pool: shared-queue with 2 threads"));
+    assert!(content.contains("Compaction threshold: 4096 bytes"));
+    assert!(content.contains("Listening on 127.0.0.1:4023"));
+    let data_dir = temp_dir.path().join("conf/data");
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// `--data-dir` should be created if it doesn't exist and work with every thread pool.
+#[test]
+fn cli_data_dir() {
+    for (i, pool) in ["naive", "shared-queue", "rayon"].iter().enumerate() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = format!("127.0.0.1:{}", 4024 + i);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "sled", "--data-dir", "db", "--addr", &addr])
+            .args(&[
+                "--thread-pool",
+                pool,
+                "--threads",
+                "1",
+                "--log-level",
+                "warn",
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", &addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", &addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        let engine = fs::read_to_string(temp_dir.path().join("db/engine")).unwrap();
+        assert_eq!(engine, "sled");
+    }
+}
@@ -0,0 +1,299 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    ClientTls, KvStore, KvsClient, KvsClientOptions, KvsEngine, KvsError, KvsServer, Result,
+    ServerTls,
+};
+use rcgen::CertifiedKey;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Clones of a client should share its connections and pipeline their requests
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4010".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    tokio::spawn(KvsServer::new(store).run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let client =
+        KvsClient::connect_with_options(addr, KvsClientOptions::new().pool_size(2)).await?;
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i).into_bytes();
+                let value = format!("value{}", i).into_bytes();
+                client.set(key.clone(), value.clone()).await?;
+                assert_eq!(client.get(key.clone()).await?, Some(value));
+                client.remove(key.clone()).await?;
+                match client.remove(key).await {
+                    Err(KvsError::StringError(msg)) => assert_eq!(msg, "Key not found"),
+                    res => panic!("Expected a missing key, got {:?}", res),
+                }
+                Ok::<(), KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    assert_eq!(client.scan(Vec::new(), None, None).await?, Vec::new());
+    Ok(())
+}
+
+// The stats should count the requests and include the engine
+#[tokio::test(flavor = "multi_thread")]
+async fn server_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4021".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    tokio::spawn(KvsServer::new(store).run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    for i in 0..10 {
+        let key = format!("key{}", i).into_bytes();
+        client.set(key.clone(), b"value".to_vec()).await?;
+        client.get(key).await?;
+    }
+    client.remove(b"key0".to_vec()).await?;
+    assert!(client.remove(b"key0".to_vec()).await.is_err());
+    client.scan(Vec::new(), None, None).await?;
+
+    let stats = client.stats().await?;
+    assert_eq!(stats.sets, 10);
+    assert_eq!(stats.gets, 10);
+    assert_eq!(stats.removes, 2);
+    assert_eq!(stats.scans, 1);
+    assert_eq!(stats.errors, 1);
+    // the stats request itself isn't counted until it's answered
+    assert_eq!(stats.requests, 23);
+    assert_eq!(stats.latency.count, 23);
+    assert!(stats.connections >= 1);
+
+    let engine = &stats.engine;
+    assert!(engine.disk_usage > 0);
+    assert_eq!(engine.thread_pool.threads, 4);
+    // every request ran in the pool, though the last jobs may not be counted yet
+    assert!(engine.thread_pool.completed >= 20);
+    let compaction = engine.compaction.as_ref().unwrap();
+    assert_eq!(compaction.finished, 0);
+    assert!(compaction.uncompacted > 0);
+    assert!(engine.cache.is_some());
+
+    let metrics = stats.to_prometheus();
+    assert!(metrics.contains("kvs_requests_total{op=\"set\"} 10\n"));
+    assert!(metrics.contains("kvs_request_duration_seconds_count 23\n"));
+    assert!(metrics.contains("# TYPE kvs_disk_usage_bytes gauge\n"));
+    Ok(())
+}
+
+// Dropping a client should close its connections on both sides
+#[tokio::test(flavor = "multi_thread")]
+async fn drop_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4027".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    tokio::spawn(KvsServer::new(store).run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let observer =
+        KvsClient::connect_with_options(addr, KvsClientOptions::new().pool_size(1)).await?;
+    let client =
+        KvsClient::connect_with_options(addr, KvsClientOptions::new().pool_size(4)).await?;
+    for i in 0..8 {
+        client
+            .set(format!("key{}", i).into_bytes(), b"value".to_vec())
+            .await?;
+    }
+    assert_eq!(observer.stats().await?.active_connections, 5);
+
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let stats = observer.stats().await?;
+    assert_eq!(stats.active_connections, 1);
+    assert_eq!(stats.connections, 5);
+    Ok(())
+}
+
+// A request which can't be parsed should fail alone if its ID can be read, while a
+// frame without an ID closes the connection
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4030".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    tokio::spawn(KvsServer::new(store).run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    for frame in &[
+        r#"{"id":1,"body":{"Unknown":{}}}"#,
+        r#"{"id":2,"body":{"Get":{"key":[107]}}}"#,
+    ] {
+        stream.write_u32(frame.len() as u32).await?;
+        stream.write_all(frame.as_bytes()).await?;
+    }
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        let mut frame = vec![0; stream.read_u32().await? as usize];
+        stream.read_exact(&mut frame).await?;
+        responses.push(String::from_utf8(frame)?);
+    }
+    responses.sort();
+    assert!(responses[0].starts_with(r#"{"id":1,"body":{"Err":"Invalid request: "#));
+    assert_eq!(responses[1], r#"{"id":2,"body":{"Get":null}}"#);
+
+    let frame = r#"{"body":"Stats"}"#;
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame.as_bytes()).await?;
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+// Shutting down should answer the requests in flight and flush the engine
+#[tokio::test(flavor = "multi_thread")]
+async fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4011".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let server = KvsServer::new(store);
+    let handle = server.shutdown_handle();
+    let server = tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i).into_bytes();
+                client.set(key, b"value".to_vec()).await.is_ok()
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    handle.shutdown();
+    server.await.unwrap()?;
+    assert!(KvsClient::connect(addr).await.is_err());
+
+    // every acknowledged write must be in the store
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for (i, task) in tasks.into_iter().enumerate() {
+        if task.await.unwrap() {
+            let key = format!("key{}", i).into_bytes();
+            assert_eq!(store.get(key).await?, Some(b"value".to_vec()));
+        }
+    }
+    Ok(())
+}
+
+// Shutting down shouldn't wait for long on a client which doesn't read its
+// responses
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_with_stalled_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4028".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    store.set(b"key".to_vec(), vec![0; 1024 * 1024]).await?;
+    let server = KvsServer::new(store);
+    let handle = server.shutdown_handle();
+    let server = tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // far more responses than the socket buffers take
+    let mut stream = TcpStream::connect(addr).await?;
+    for id in 0..100 {
+        let frame = format!(
+            r#"{{"id":{},"body":{{"Get":{{"key":[107,101,121]}}}}}}"#,
+            id
+        );
+        stream
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(frame.as_bytes()).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    handle.shutdown();
+    tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("the server should stop after the shutdown timeout")
+        .unwrap()?;
+    Ok(())
+}
+
+// A server with TLS and a token should only serve clients which trust its
+// certificate and send the token
+#[tokio::test(flavor = "multi_thread")]
+async fn tls_and_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4012".parse().unwrap();
+    let (cert, key) = self_signed_cert(temp_dir.path());
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path().join("data"), 4)?;
+    let server = KvsServer::new(store)
+        .tls(ServerTls::from_pem_files(&cert, &key)?)
+        .auth_token("secret".to_owned());
+    tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let tls = ClientTls::from_ca_file(&cert)?;
+    let options = KvsClientOptions::new().tls(tls.clone());
+    let client =
+        KvsClient::connect_with_options(addr, options.clone().auth_token("secret".to_owned()))
+            .await?;
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(
+        client.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+
+    let client =
+        KvsClient::connect_with_options(addr, tls_client_options(&tls, "localhost")?).await?;
+    assert_eq!(
+        client.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+
+    match KvsClient::connect_with_options(addr, options.clone().auth_token("wrong".to_owned()))
+        .await
+    {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Invalid auth token"),
+        res => panic!("Expected a rejected token, got {:?}", res.map(|_| ())),
+    }
+    let client = KvsClient::connect_with_options(addr, options).await?;
+    match client.get(b"key1".to_vec()).await {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "Authentication required"),
+        res => panic!("Expected an unauthenticated request, got {:?}", res),
+    }
+
+    // plain TCP and certificates for other names are refused
+    let plain = KvsClientOptions::new().auth_token("secret".to_owned());
+    assert!(KvsClient::connect_with_options(addr, plain).await.is_err());
+    let other = tls_client_options(&tls, "example.com")?;
+    assert!(KvsClient::connect_with_options(addr, other).await.is_err());
+    Ok(())
+}
+
+fn tls_client_options(tls: &ClientTls, domain: &str) -> Result<KvsClientOptions> {
+    Ok(KvsClientOptions::new()
+        .tls(tls.clone().domain(domain)?)
+        .auth_token("secret".to_owned()))
+}
+
+/// Writes a self-signed certificate for localhost and its key to `dir`.
+fn self_signed_cert(dir: &Path) -> (PathBuf, PathBuf) {
+    let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_path, cert.pem()).unwrap();
+    fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
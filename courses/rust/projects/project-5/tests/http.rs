@@ -0,0 +1,159 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsClient, KvsServer, Result, ServerStats};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Starts a server with a new store in `temp_dir`, serving the HTTP API on
+/// `http_addr`.
+async fn start_server(
+    temp_dir: &TempDir,
+    addr: SocketAddr,
+    http_addr: SocketAddr,
+    auth_token: Option<&str>,
+) -> Result<()> {
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let mut server = KvsServer::new(store).http(http_addr);
+    if let Some(token) = auth_token {
+        server = server.auth_token(token.to_owned());
+    }
+    tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    Ok(())
+}
+
+/// Sends a request on a new connection and returns the status, the headers and
+/// the body of the response.
+async fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[&str],
+    body: &str,
+) -> Result<(u16, String, String)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for header in headers {
+        req.push_str(header);
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+    stream.write_all(req.as_bytes()).await?;
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await?;
+    let (head, body) = resp.split_at(resp.find("\r\n\r\n").unwrap());
+    let status = head[9..12].parse().unwrap();
+    Ok((status, head.to_owned(), body[4..].to_owned()))
+}
+
+// The HTTP API should get, set and remove keys of the same engine as the main
+// protocol
+#[tokio::test]
+async fn http_kv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4017".parse().unwrap();
+    let http_addr = "127.0.0.1:4018".parse().unwrap();
+    start_server(&temp_dir, addr, http_addr, None).await?;
+
+    let (status, _, body) = request(http_addr, "GET", "/health", &[], "").await?;
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"{"status":"ok"}"#);
+
+    let (status, _, _) = request(http_addr, "PUT", "/kv/key1", &[], "value1").await?;
+    assert_eq!(status, 204);
+    let (status, head, body) = request(http_addr, "GET", "/kv/key1", &[], "").await?;
+    assert_eq!(status, 200);
+    assert!(head.contains("content-type: application/octet-stream"));
+    assert_eq!(body, "value1");
+
+    // the keys are shared with kvs-client
+    let client = KvsClient::connect(addr).await?;
+    assert_eq!(
+        client.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    client.set(b"a key".to_vec(), b"value2".to_vec()).await?;
+    let (status, _, body) = request(http_addr, "GET", "/kv/a%20key", &[], "").await?;
+    assert_eq!(status, 200);
+    assert_eq!(body, "value2");
+
+    let (status, _, _) = request(http_addr, "DELETE", "/kv/key1", &[], "").await?;
+    assert_eq!(status, 204);
+    let (status, _, body) = request(http_addr, "GET", "/kv/key1", &[], "").await?;
+    assert_eq!(status, 404);
+    assert_eq!(body, r#"{"error":"Key not found"}"#);
+    let (status, _, _) = request(http_addr, "DELETE", "/kv/key1", &[], "").await?;
+    assert_eq!(status, 404);
+    assert_eq!(client.get(b"key1".to_vec()).await?, None);
+
+    let (status, _, _) = request(http_addr, "PUT", "/kv/key3?ttl=1", &[], "value3").await?;
+    assert_eq!(status, 204);
+    let (status, _, _) = request(http_addr, "GET", "/kv/key3", &[], "").await?;
+    assert_eq!(status, 200);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let (status, _, _) = request(http_addr, "GET", "/kv/key3", &[], "").await?;
+    assert_eq!(status, 404);
+    let (status, _, _) = request(http_addr, "PUT", "/kv/key3?ttl=x", &[], "value3").await?;
+    assert_eq!(status, 400);
+
+    let (status, head, _) = request(http_addr, "POST", "/kv/key1", &[], "").await?;
+    assert_eq!(status, 405);
+    assert!(head.contains("allow: GET, PUT, DELETE"));
+    let (status, _, _) = request(http_addr, "GET", "/other", &[], "").await?;
+    assert_eq!(status, 404);
+
+    let (status, _, body) = request(http_addr, "GET", "/stats", &[], "").await?;
+    assert_eq!(status, 200);
+    let stats: ServerStats = serde_json::from_str(&body)?;
+    assert_eq!(stats.gets, 7);
+    assert_eq!(stats.sets, 4);
+    assert_eq!(stats.removes, 2);
+    assert_eq!(stats.errors, 1);
+    // at least the connection of this request, and more of the client pool
+    assert!(stats.active_connections >= 1);
+    assert!(stats.connections > stats.active_connections);
+
+    let (status, head, body) = request(http_addr, "GET", "/metrics", &[], "").await?;
+    assert_eq!(status, 200);
+    assert!(head.contains("content-type: text/plain; version=0.0.4"));
+    assert!(body.contains("kvs_requests_total{op=\"get\"} 7\n"));
+    assert!(body.contains("kvs_request_duration_seconds_bucket{le=\"+Inf\"} 13\n"));
+    assert!(body.contains("# TYPE kvs_compaction_duration_seconds histogram\n"));
+    Ok(())
+}
+
+// A server with a token should require it as a bearer token, except for `/health`
+#[tokio::test]
+async fn http_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4019".parse().unwrap();
+    let http_addr = "127.0.0.1:4020".parse().unwrap();
+    start_server(&temp_dir, addr, http_addr, Some("secret")).await?;
+
+    let (status, _, _) = request(http_addr, "GET", "/health", &[], "").await?;
+    assert_eq!(status, 200);
+    let (status, head, _) = request(http_addr, "PUT", "/kv/key1", &[], "value1").await?;
+    assert_eq!(status, 401);
+    assert!(head.contains("www-authenticate: Bearer"));
+    let wrong = ["Authorization: Bearer wrong"];
+    let (status, _, _) = request(http_addr, "GET", "/stats", &wrong, "").await?;
+    assert_eq!(status, 401);
+
+    let auth = ["Authorization: Bearer secret"];
+    let (status, _, _) = request(http_addr, "PUT", "/kv/key1", &auth, "value1").await?;
+    assert_eq!(status, 204);
+    let (status, _, body) = request(http_addr, "GET", "/kv/key1", &auth, "").await?;
+    assert_eq!(status, 200);
+    assert_eq!(body, "value1");
+    Ok(())
+}
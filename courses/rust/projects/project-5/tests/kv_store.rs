@@ -1,115 +1,94 @@
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{
+    Compression, IndexMode, KvStore, KvStoreOptions, KvsEngine, KvsError, LogFormat, Result,
+    SledKvsEngine, SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
-use tokio::prelude::*;
-use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
 
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
-    );
-    assert_eq!(
-        store.get("key2".to_owned()).wait()?,
-        Some("value2".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
-    );
-    assert_eq!(
-        store.get("key2".to_owned()).wait()?,
-        Some("value2".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
 
     Ok(())
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+#[tokio::test]
+async fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
-    );
-    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
-    );
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
-    );
-    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
-    assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value3".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value3".to_vec()));
 
     Ok(())
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+#[tokio::test]
+async fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+#[tokio::test]
+async fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).wait().is_err());
+    assert!(store.remove(b"key1".to_vec()).await.is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+#[tokio::test]
+async fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert!(store.remove("key1".to_owned()).wait().is_ok());
-    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert!(store.remove(b"key1".to_vec()).await.is_ok());
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
     Ok(())
 }
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+#[tokio::test]
+async fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
@@ -127,9 +106,9 @@ fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value).wait()?;
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
+            store.set(key, value).await?;
         }
 
         let new_size = dir_size();
@@ -143,8 +122,11 @@ fn compaction() -> Result<()> {
         // reopen and check content
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(
+                store.get(key).await?,
+                Some(format!("{}", iter).into_bytes())
+            );
         }
         return Ok(());
     }
@@ -152,88 +134,1238 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
-#[test]
-fn concurrent_set() -> Result<()> {
+// Should keep accepting writes while a compaction runs in the background
+#[tokio::test]
+async fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a threshold that is not reached below, so only the manual compaction runs
+    let options = KvStoreOptions::new().compaction_threshold(1024 * 1024 * 1024);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, options)?;
+    for iter in 0..10 {
+        for key_id in 0..1000 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .await?;
+        }
+    }
+    assert_eq!(store.compaction_stats().finished, 0);
+    assert!(store.compaction_stats().uncompacted > 0);
+
+    let compaction = store.compact();
+    for key_id in 0..1000 {
+        store
+            .set(format!("key{}", key_id).into_bytes(), b"new".to_vec())
+            .await?;
+    }
+    compaction.await?;
+    let stats = store.compaction_stats();
+    assert_eq!(stats.finished, 1);
+    assert_eq!(stats.failed, 0);
+    assert_eq!(stats.durations.count, 1);
+    assert!(!stats.running);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).await?,
+            Some(b"new".to_vec())
+        );
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).await?,
+            Some(b"new".to_vec())
+        );
+    }
+    Ok(())
+}
+
+// A snapshot should see the store as it was taken, even after a compaction
+#[tokio::test]
+async fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{:02}", key_id).into_bytes(), b"old".to_vec())
+            .await?;
+    }
+    let snapshot = store.snapshot();
+
+    for key_id in 0..50 {
+        store
+            .set(format!("key{:02}", key_id).into_bytes(), b"new".to_vec())
+            .await?;
+    }
+    store.remove(b"key99".to_vec()).await?;
+    store.set(b"key100".to_vec(), b"new".to_vec()).await?;
+    store.compact().await?;
+    // the snapshot pins the log file written before it
+    assert!(temp_dir.path().join("1.log").exists());
+
+    assert_eq!(store.get(b"key00".to_vec()).await?, Some(b"new".to_vec()));
+    assert_eq!(snapshot.get(b"key00")?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get(b"key99")?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get(b"key100")?, None);
+    assert_eq!(snapshot.len()?, 100);
+    assert_eq!(
+        snapshot.scan(b"key49", Some(b"key51"), None)?,
+        vec![
+            (b"key49".to_vec(), b"old".to_vec()),
+            (b"key50".to_vec(), b"old".to_vec()),
+        ]
+    );
+    assert_eq!(snapshot.scan_prefix(b"key1", None)?.len(), 10);
+    assert!(snapshot
+        .iter()
+        .all(|pair| pair.unwrap().1 == b"old".to_vec()));
+
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get(b"key00".to_vec()).await?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key50".to_vec()).await?, Some(b"old".to_vec()));
+    Ok(())
+}
+
+// A backup should be a compacted copy of the store that can be opened
+#[tokio::test]
+async fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .await?;
+        }
+    }
+    store.remove(b"key0".to_vec()).await?;
+    store
+        .set_with_ttl(b"key1".to_vec(), b"ttl".to_vec(), Duration::from_secs(3600))
+        .await?;
+    store.backup(backup_dir.path().to_owned()).await?;
+    assert!(store.backup(backup_dir.path().to_owned()).await.is_err());
+
+    let backup = KvStore::<RayonThreadPool>::open(backup_dir.path(), 1)?;
+    assert_eq!(backup.get(b"key0".to_vec()).await?, None);
+    assert_eq!(backup.get(b"key1".to_vec()).await?, Some(b"ttl".to_vec()));
+    assert_eq!(backup.get(b"key2".to_vec()).await?, Some(b"9".to_vec()));
+    assert_eq!(backup.compaction_stats().uncompacted, 0);
+    let snapshot = backup.snapshot();
+    assert_eq!(snapshot.len()?, 99);
+    Ok(())
+}
+
+// Snapshots taken while keys are written and compacted should see every key as
+// it was when they were taken
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_during_writes() -> Result<()> {
+    for &mode in &[IndexMode::Memory, IndexMode::Sparse] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .index_mode(mode)
+            .compaction_threshold(100_000);
+        let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, options)?;
+        for key_id in 0..5000 {
+            store
+                .set(format!("key{:04}", key_id).into_bytes(), b"0".to_vec())
+                .await?;
+        }
+
+        let writes = {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for round in 1..=3 {
+                    for key_id in 0..5000 {
+                        let key = format!("key{:04}", key_id).into_bytes();
+                        store.set(key, round.to_string().into_bytes()).await?;
+                    }
+                }
+                Ok::<_, KvsError>(())
+            })
+        };
+        let mut snapshots = Vec::new();
+        while !writes.is_finished() {
+            snapshots.push(store.snapshot());
+            tokio::task::yield_now().await;
+        }
+        writes.await.unwrap()?;
+
+        for snapshot in snapshots {
+            let rounds = snapshot
+                .iter()
+                .map(|pair| Ok(String::from_utf8(pair?.1)?.parse().unwrap()))
+                .collect::<Result<Vec<u32>>>()?;
+            // keys are set in order in each round, so a snapshot sees the keys up to
+            // one set in a round and the rest set in the round before
+            assert_eq!(rounds.len(), 5000);
+            assert!(rounds.windows(2).all(|w| w[0] == w[1] || w[0] == w[1] + 1));
+            assert!(rounds[0] <= rounds[4999] + 1);
+        }
+    }
+    Ok(())
+}
+
+// Should load the index of a compacted log from its hint file, or replay the log
+// if the hint file is invalid
+#[tokio::test]
+async fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..100 {
+        store
+            .set(
+                format!("key{}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+            .await?;
+    }
+    store.remove(b"key0".to_vec()).await?;
+    store.compact().await?;
+    store.set(b"key1".to_vec(), b"new".to_vec()).await?;
+    drop(store);
+
+    // 1.log is compacted into 2.log and new writes go to 3.log
+    let hint_path = temp_dir.path().join("2.hint");
+    assert!(hint_path.exists());
+
+    let check = async || -> Result<()> {
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(store.get(b"key0".to_vec()).await?, None);
+        assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"new".to_vec()));
+        for key_id in 2..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes()).await?,
+                Some(format!("value{}", key_id).into_bytes())
+            );
+        }
+        Ok(())
+    };
+    check().await?;
+
+    let mut hint = fs::read(&hint_path)?;
+    let len = hint.len();
+    hint[len / 2] ^= 0xff;
+    fs::write(&hint_path, hint)?;
+    check().await?;
+
+    fs::remove_file(&hint_path)?;
+    check().await
+}
+
+// Should store keys and values that are not valid UTF-8
+#[tokio::test]
+async fn binary_keys_and_values() -> Result<()> {
+    for &format in &[LogFormat::Binary, LogFormat::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().log_format(format);
+        let store =
+            KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        let key = vec![0xff, 0x00, 0xfe];
+        let value = vec![0x80, 0x81, 0x00, 0x0a];
+        store.set(key.clone(), value.clone()).await?;
+        store.set(b"".to_vec(), b"".to_vec()).await?;
+        store.set(b"text".to_vec(), value.clone()).await?;
+        assert_eq!(store.get(key.clone()).await?, Some(value.clone()));
+        assert_eq!(store.get(b"".to_vec()).await?, Some(b"".to_vec()));
+        store.compact().await?;
+        drop(store);
+
+        let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+        assert_eq!(store.get(key.clone()).await?, Some(value.clone()));
+        assert_eq!(store.get(b"text".to_vec()).await?, Some(value.clone()));
+        assert_eq!(
+            store.scan_prefix(vec![0xff], None).await?,
+            vec![(key, value)]
+        );
+    }
+    Ok(())
+}
+
+// Should read JSON logs written with string keys and values by earlier versions
+// Expired keys should be invisible, and dropped by compaction
+#[tokio::test]
+async fn expiring_keys() -> Result<()> {
+    for &format in &[LogFormat::Binary, LogFormat::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().log_format(format);
+        let store =
+            KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        let ttl = Duration::from_millis(200);
+        store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+        store
+            .set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), ttl)
+            .await?;
+        store
+            .set_with_ttl(b"key3".to_vec(), b"value3".to_vec(), ttl)
+            .await?;
+        store
+            .set_with_ttl(b"key4".to_vec(), b"value4".to_vec(), ttl)
+            .await?;
+        // setting without a TTL makes the key persistent
+        store.set(b"key4".to_vec(), b"value5".to_vec()).await?;
+        store
+            .set_with_ttl(
+                b"key5".to_vec(),
+                b"value6".to_vec(),
+                Duration::from_secs(3600),
+            )
+            .await?;
+        assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+        drop(store);
+
+        // expiration times survive a reopen
+        let store =
+            KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(store.get(b"key2".to_vec()).await?, None);
+        match store.remove(b"key3".to_vec()).await {
+            Err(KvsError::KeyNotFound) => {}
+            _ => panic!("Removing an expired key should fail"),
+        }
+        let keys: Vec<Vec<u8>> = store
+            .scan_prefix(b"key".to_vec(), None)
+            .await?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![b"key1".to_vec(), b"key4".to_vec(), b"key5".to_vec()]
+        );
+
+        store.compact().await?;
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+        assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+        assert_eq!(store.get(b"key3".to_vec()).await?, None);
+        assert_eq!(store.get(b"key4".to_vec()).await?, Some(b"value5".to_vec()));
+        assert_eq!(store.get(b"key5".to_vec()).await?, Some(b"value6".to_vec()));
+        assert_eq!(
+            store.get_with_ttl(b"key4".to_vec()).await?,
+            Some((b"value5".to_vec(), None))
+        );
+        match store.get_with_ttl(b"key5".to_vec()).await? {
+            Some((_, Some(ttl))) => assert!(ttl > Duration::from_secs(3500)),
+            res => panic!("Expected a key with a TTL, got {:?}", res),
+        }
+        assert_eq!(store.get_with_ttl(b"key3".to_vec()).await?, None);
+        assert_eq!(store.compaction_stats().uncompacted, 0);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    Ok(())
+}
+
+// Should get key/value pairs in order by range or prefix
+#[tokio::test]
+async fn scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key in &["b1", "a1", "b3", "b2", "c1"] {
+        store
+            .set(key.as_bytes().to_vec(), format!("v{}", key).into_bytes())
+            .await?;
+    }
+    store.remove(b"b2".to_vec()).await?;
+
+    let pairs = |keys: &[&str]| -> Vec<(Vec<u8>, Vec<u8>)> {
+        keys.iter()
+            .map(|key| (key.as_bytes().to_vec(), format!("v{}", key).into_bytes()))
+            .collect()
+    };
+    assert_eq!(
+        store
+            .scan(b"a2".to_vec(), Some(b"c1".to_vec()), None)
+            .await?,
+        pairs(&["b1", "b3"])
+    );
+    assert_eq!(
+        store.scan(b"".to_vec(), None, Some(2)).await?,
+        pairs(&["a1", "b1"])
+    );
+    assert_eq!(
+        store.scan_prefix(b"b".to_vec(), None).await?,
+        pairs(&["b1", "b3"])
+    );
+    assert_eq!(store.scan_prefix(b"d".to_vec(), None).await?, vec![]);
+    assert_eq!(
+        store
+            .scan_keys(b"a2".to_vec(), Some(b"c2".to_vec()), Some(2))
+            .await?,
+        vec![b"b1".to_vec(), b"b3".to_vec()]
+    );
+
+    Ok(())
+}
+
+// Should apply all operations in a batch
+#[tokio::test]
+async fn write_batch() -> Result<()> {
+    for &format in &[LogFormat::Binary, LogFormat::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().log_format(format);
+        let mut store =
+            KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"key2".to_vec(), b"value2".to_vec())
+            .remove(b"key1".to_vec())
+            .set(b"key3".to_vec(), b"value3".to_vec())
+            .set(b"key2".to_vec(), b"value4".to_vec());
+        store.write_batch(batch).await?;
+
+        for _ in 0..2 {
+            assert_eq!(store.get(b"key1".to_vec()).await?, None);
+            assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value4".to_vec()));
+            assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+
+            // Open from disk again and check persistent data
+            drop(store);
+            store =
+                KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        }
+    }
+
+    Ok(())
+}
+
+// Should drop a partially written batch as a whole
+async fn check_compare_and_swap<E: KvsEngine>(engine: &E) -> Result<()> {
+    let cas = |expected: Option<&[u8]>, new: Option<&[u8]>| {
+        engine.compare_and_swap(
+            b"key1".to_vec(),
+            expected.map(|v| v.to_vec()),
+            new.map(|v| v.to_vec()),
+        )
+    };
+    let assert_condition_failed = |res: Result<()>| match res {
+        Err(KvsError::ConditionFailed) => {}
+        res => panic!("Expected a failed condition, got {:?}", res),
+    };
+
+    // the key must not exist
+    cas(None, Some(b"value1")).await?;
+    assert_condition_failed(cas(None, Some(b"value2")).await);
+    assert_eq!(
+        engine.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+
+    assert_condition_failed(cas(Some(b"value2"), Some(b"value3")).await);
+    cas(Some(b"value1"), Some(b"value2")).await?;
+    assert_eq!(
+        engine.get(b"key1".to_vec()).await?,
+        Some(b"value2".to_vec())
+    );
+
+    assert_condition_failed(cas(Some(b"value1"), None).await);
+    cas(Some(b"value2"), None).await?;
+    assert_eq!(engine.get(b"key1".to_vec()).await?, None);
+    cas(None, None).await?;
+
+    // an expired key doesn't exist
+    engine
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value4".to_vec(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_condition_failed(cas(Some(b"value4"), Some(b"value5")).await);
+    cas(None, Some(b"value5")).await?;
+    assert_eq!(
+        engine.get(b"key1".to_vec()).await?,
+        Some(b"value5".to_vec())
+    );
+    Ok(())
+}
+
+// Compare-and-swap should only write if the current value is the expected one
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check_compare_and_swap(&store).await?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value5".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine =
+        SledKvsEngine::<RayonThreadPool>::new(sled::Db::start_default(temp_dir.path())?, 1)?;
+    check_compare_and_swap(&engine).await
+}
+
+// Should remove expired keys of the sled engine from disk, but not the ones set
+// again without a TTL
+#[tokio::test]
+async fn sled_remove_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let ttl = Duration::from_millis(100);
+    for key_id in 0..10 {
+        engine
+            .set_with_ttl(
+                format!("key{}", key_id).into_bytes(),
+                b"value".to_vec(),
+                ttl,
+            )
+            .await?;
+    }
+    engine
+        .set_with_ttl(
+            b"later".to_vec(),
+            b"value".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    engine.set(b"key0".to_vec(), b"persistent".to_vec()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        engine.scan_keys(b"".to_vec(), None, None).await?,
+        vec![b"key0".to_vec(), b"later".to_vec()]
+    );
+
+    assert_eq!(engine.remove_expired().await?, 9);
+    assert_eq!(engine.remove_expired().await?, 0);
+    assert_eq!(
+        engine.get(b"key0".to_vec()).await?,
+        Some(b"persistent".to_vec())
+    );
+    assert_eq!(
+        engine.get(b"later".to_vec()).await?,
+        Some(b"value".to_vec())
+    );
+
+    // the data tree only has the live keys
+    drop(engine);
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let keys = db.iter().keys().collect::<sled::Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![b"key0".to_vec(), b"later".to_vec()]);
+    Ok(())
+}
+
+// Should apply all operations in a sled batch at once, clear the TTLs of the keys
+// it sets, and keep them after the database is opened again
+#[tokio::test]
+async fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    engine.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    engine
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_millis(100),
+        )
+        .await?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .remove(b"key1".to_vec())
+        .set(b"key2".to_vec(), b"value3".to_vec())
+        .set(b"key3".to_vec(), b"value4".to_vec());
+    engine.write_batch(batch).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // readers never see a batch partially applied
+    let reader = {
+        let engine = engine.clone();
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                let pairs = engine.scan_prefix(b"batch".to_vec(), None).await?;
+                assert!(pairs.iter().all(|(_, value)| *value == pairs[0].1));
+            }
+            Ok::<_, KvsError>(())
+        })
+    };
+    for i in 0..50 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..100 {
+            batch.set(
+                format!("batch{:03}", key_id).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            );
+        }
+        engine.write_batch(batch).await?;
+    }
+    reader.await.expect("reader panicked")?;
+
+    drop(engine);
+    let engine = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(engine.get(b"key1".to_vec()).await?, None);
+    assert_eq!(
+        engine.get_with_ttl(b"key2".to_vec()).await?,
+        Some((b"value3".to_vec(), None))
+    );
+    assert_eq!(
+        engine.get(b"key3".to_vec()).await?,
+        Some(b"value4".to_vec())
+    );
+    assert_eq!(
+        engine.get(b"batch099".to_vec()).await?,
+        Some(b"value49".to_vec())
+    );
+    Ok(())
+}
+
+// Concurrent compare-and-swaps of a counter should not lose any increment
+#[tokio::test]
+async fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let increments: Vec<_> = (0..100)
+        .map(|_| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                loop {
+                    let current = store.get(b"counter".to_vec()).await?;
+                    let next = current
+                        .as_ref()
+                        .map_or(0, |v| String::from_utf8_lossy(v).parse::<u32>().unwrap())
+                        + 1;
+                    match store
+                        .compare_and_swap(
+                            b"counter".to_vec(),
+                            current,
+                            Some(next.to_string().into_bytes()),
+                        )
+                        .await
+                    {
+                        Ok(()) => return Ok(()),
+                        Err(KvsError::ConditionFailed) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            })
+        })
+        .collect();
+    for increment in increments {
+        increment.await.unwrap()?;
+    }
+    assert_eq!(store.get(b"counter".to_vec()).await?, Some(b"100".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"value2".to_vec())
+        .set(b"key2".to_vec(), b"value2".to_vec());
+    store.write_batch(batch).await?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// Should read logs written in the JSON format after switching to the binary format
+#[tokio::test]
+async fn switch_log_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json = KvStoreOptions::new().log_format(LogFormat::Json);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, json)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+
+    drop(store);
+    let binary = KvStoreOptions::new().log_format(LogFormat::Binary);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, binary)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    store.set(b"key2".to_vec(), b"value3".to_vec()).await?;
+
+    // Trigger a compaction which rewrites the JSON records in the binary format
+    for iter in 0..2000 {
+        store
+            .set(b"key3".to_vec(), format!("{:01000}", iter).into_bytes())
+            .await?;
+    }
+    store.set(b"key3".to_vec(), b"value".to_vec()).await?;
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value3".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+fn log_size(dir: &std::path::Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Should compress values in new records and in compactions, and read logs with
+// records of mixed compressions
+#[tokio::test]
+async fn compression() -> Result<()> {
+    let value = |i: u32| format!("{{\"id\":{},\"tags\":[{}]}}", i, "\"tag\",".repeat(100));
+    for &compression in &[Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i).into_bytes(), value(i).into_bytes())
+                .await?;
+        }
+        let uncompressed = log_size(temp_dir.path());
+
+        drop(store);
+        let options = KvStoreOptions::new().compression(compression);
+        let store =
+            KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+        for i in 100..200 {
+            store
+                .set(format!("key{}", i).into_bytes(), value(i).into_bytes())
+                .await?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(b"batch".to_vec(), value(200).into_bytes());
+        batch.set(b"short".to_vec(), b"x".to_vec());
+        store.write_batch(batch).await?;
+        store
+            .set_with_ttl(
+                b"expiring".to_vec(),
+                value(201).into_bytes(),
+                Duration::from_secs(3600),
+            )
+            .await?;
+        assert!(log_size(temp_dir.path()) < uncompressed * 3 / 2);
+
+        // the compaction compresses the records written without compression
+        store.compact().await?;
+        assert!(log_size(temp_dir.path()) < uncompressed / 2);
+
+        drop(store);
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", i).into_bytes()).await?,
+                Some(value(i).into_bytes())
+            );
+        }
+        assert_eq!(
+            store.get(b"batch".to_vec()).await?,
+            Some(value(200).into_bytes())
+        );
+        assert_eq!(store.get(b"short".to_vec()).await?, Some(b"x".to_vec()));
+        assert_eq!(
+            store.get(b"expiring".to_vec()).await?,
+            Some(value(201).into_bytes())
+        );
+
+        // JSON logs are never compressed
+        drop(store);
+        let json = options.log_format(LogFormat::Json);
+        let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, json)?;
+        store.compact().await?;
+        assert!(log_size(temp_dir.path()) > uncompressed);
+        assert_eq!(
+            store.get(b"key0".to_vec()).await?,
+            Some(value(0).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Should serve repeated reads from the value cache and never return outdated values
+#[tokio::test]
+async fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_capacity(64 * 1024);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, options)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    for _ in 0..3 {
+        assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!(stats.size, 6);
+
+    store.set(b"key1".to_vec(), b"value2".to_vec()).await?;
+    assert_eq!(store.cache_stats().size, 0);
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value2".to_vec()));
+    store.remove(b"key1".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+
+    // The cache stays bounded and compactions don't leave outdated values
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id).into_bytes();
+            store
+                .set(key.clone(), format!("{:0100}", iter).into_bytes())
+                .await?;
+            assert_eq!(
+                store.get(key).await?,
+                Some(format!("{:0100}", iter).into_bytes())
+            );
+        }
+        if iter % 10 == 0 {
+            store.compact().await?;
+        }
+        assert!(store.cache_stats().size <= 64 * 1024);
+    }
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(
+            store.get(key).await?,
+            Some(format!("{:0100}", 99).into_bytes())
+        );
+    }
+
+    drop(store);
+    let options = KvStoreOptions::new().cache_capacity(0);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    store.get(b"key0".to_vec()).await?;
+    store.get(b"key0".to_vec()).await?;
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.size), (0, 0, 0));
+
+    // The capacity bounds the whole cache rather than each part of it
+    drop(store);
+    let options = KvStoreOptions::new().cache_capacity(150);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    store.get(b"key0".to_vec()).await?;
+    store.get(b"key0".to_vec()).await?;
+    assert_eq!(store.cache_stats().hits, 1);
+    store.get(b"key1".to_vec()).await?;
+    assert_eq!(store.cache_stats().size, 100);
+
+    Ok(())
+}
+
+// Should look up compacted keys in the key file in the sparse index mode
+#[tokio::test]
+async fn sparse_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sparse = KvStoreOptions::new().index_mode(IndexMode::Sparse);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, sparse.clone())?;
+    for key_id in 0..1000 {
+        store
+            .set(
+                format!("key{:04}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+            .await?;
+    }
+    store.compact().await?;
+
+    // Changes since the compaction override the key file
+    store.set(b"key0001".to_vec(), b"new".to_vec()).await?;
+    store.remove(b"key0002".to_vec()).await?;
+    store.set(b"key0002".to_vec(), b"again".to_vec()).await?;
+    store.remove(b"key0003".to_vec()).await?;
+    store
+        .set(b"key1000".to_vec(), b"value1000".to_vec())
+        .await?;
+    let check = async |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        assert_eq!(
+            store.get(b"key0000".to_vec()).await?,
+            Some(b"value0".to_vec())
+        );
+        assert_eq!(store.get(b"key0001".to_vec()).await?, Some(b"new".to_vec()));
+        assert_eq!(
+            store.get(b"key0002".to_vec()).await?,
+            Some(b"again".to_vec())
+        );
+        assert_eq!(store.get(b"key0003".to_vec()).await?, None);
+        assert_eq!(
+            store.get(b"key0999".to_vec()).await?,
+            Some(b"value999".to_vec())
+        );
+        assert_eq!(
+            store.get(b"key1000".to_vec()).await?,
+            Some(b"value1000".to_vec())
+        );
+        assert_eq!(store.get(b"key".to_vec()).await?, None);
+        assert_eq!(store.get(b"key2000".to_vec()).await?, None);
+        match store.remove(b"key0003".to_vec()).await {
+            Err(KvsError::KeyNotFound) => {}
+            _ => panic!("Removing a removed key should fail"),
+        }
+
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+            pairs.into_iter().map(|(key, _)| key).collect()
+        };
+        let pairs = store
+            .scan(b"key0000".to_vec(), Some(b"key0005".to_vec()), None)
+            .await?;
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key0000".to_vec(), b"value0".to_vec()),
+                (b"key0001".to_vec(), b"new".to_vec()),
+                (b"key0002".to_vec(), b"again".to_vec()),
+                (b"key0004".to_vec(), b"value4".to_vec()),
+            ]
+        );
+        let pairs = store.scan_prefix(b"key09".to_vec(), None).await?;
+        assert_eq!(pairs.len(), 100);
+        assert_eq!(pairs[99], (b"key0999".to_vec(), b"value999".to_vec()));
+        let pairs = store.scan(b"key0990".to_vec(), None, Some(20)).await?;
+        assert_eq!(
+            keys(pairs),
+            (990..=1000)
+                .map(|key_id| format!("key{:04}", key_id).into_bytes())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(store.snapshot().len()?, 1000);
+        Ok(())
+    };
+    check(&store).await?;
+
+    // Reopen in both modes
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, sparse.clone())?;
+    check(&store).await?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    check(&store).await?;
+
+    // Compact again with a snapshot alive and writes going on
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, sparse)?;
+    let snapshot = store.snapshot();
+    let compaction = store.compact();
+    for key_id in 0..100 {
+        store
+            .set(
+                format!("key{:04}", key_id * 10).into_bytes(),
+                format!("value{}", key_id * 10).into_bytes(),
+            )
+            .await?;
+    }
+    compaction.await?;
+    check(&store).await?;
+    assert_eq!(snapshot.get(b"key0001")?, Some(b"new".to_vec()));
+    assert_eq!(snapshot.len()?, 1000);
+
+    Ok(())
+}
+
+// Should keep keys removed while the first compaction runs in the sparse index mode
+// removed, even if the compaction has already copied them
+#[tokio::test]
+async fn sparse_remove_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sparse = KvStoreOptions::new()
+        .index_mode(IndexMode::Sparse)
+        .compaction_threshold(1024 * 1024 * 1024);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, sparse.clone())?;
+    for key_id in 0..1000 {
+        store
+            .set(format!("key{:04}", key_id).into_bytes(), b"value".to_vec())
+            .await?;
+    }
+
+    let compaction = store.compact();
+    for key_id in 0..1000 {
+        store
+            .remove(format!("key{:04}", key_id).into_bytes())
+            .await?;
+    }
+    compaction.await?;
+    assert_eq!(store.compaction_stats().finished, 1);
+    let check = async |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{:04}", key_id).into_bytes()).await?,
+                None
+            );
+        }
+        assert_eq!(store.scan_prefix(b"key".to_vec(), None).await?, vec![]);
+        Ok(())
+    };
+    check(&store).await?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, sparse)?;
+    check(&store).await?;
+    Ok(())
+}
+
+// Should truncate a partially written record at the end of the newest log
+#[tokio::test]
+async fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    store.set(b"key2".to_vec(), b"value3".to_vec()).await?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// Should skip a corrupted record in an older log
+#[tokio::test]
+async fn skip_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    drop(store);
+    // Open again so that 1.log is no longer the newest log
+    drop(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?);
+
+    // Flip a byte in the value of the first record
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let pos = content
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("record not found");
+    content[pos] ^= 0xff;
+    fs::write(&log, content)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     // concurrent set in 8 threads
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for i in 0..10000 {
-            executor.spawn(
+    let sets: Vec<_> = (0..10000)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
                 store
-                    .set(format!("key{}", i), format!("value{}", i))
-                    .map_err(|_| ()),
-            );
-        }
-        future::ok::<(), KvsError>(())
-    }))?;
+                    .set(
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    )
+                    .await
+            })
+        })
+        .collect();
+    for set in sets {
+        set.await.unwrap()?;
+    }
 
     // We only check concurrent set in this test, so we check sequentially here
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     for i in 0..10000 {
         assert_eq!(
-            store.get(format!("key{}", i)).wait()?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", i).into_bytes()).await?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+// Should persist concurrent writes with every sync policy
+#[tokio::test]
+async fn concurrent_set_with_sync_policy() -> Result<()> {
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(2)),
+        SyncPolicy::Never,
+    ];
+    for &policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(policy);
+        let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 8, options)?;
+        let sets: Vec<_> = (0..1000)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    store
+                        .set(
+                            format!("key{}", i).into_bytes(),
+                            format!("value{}", i).into_bytes(),
+                        )
+                        .await
+                })
+            })
+            .collect();
+        for set in sets {
+            set.await.unwrap()?;
+        }
+
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", i).into_bytes()).await?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+    Ok(())
+}
+
+// Flushing should sync the writes in the log files written before a compaction
+// switched to a new one, even if writes are never synced otherwise
+#[tokio::test]
+async fn flush_after_file_switch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Never);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, options)?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i).into_bytes(), b"old".to_vec())
+            .await?;
+    }
+    store.compact().await?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i).into_bytes(), b"new".to_vec())
+            .await?;
+    }
+    store.compact().await?;
+    store.set(b"key100".to_vec(), b"new".to_vec()).await?;
+    store.flush().await?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..=100 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes()).await?,
+            Some(b"new".to_vec())
         );
     }
+    Ok(())
+}
+
+// Writes waiting for a group commit shouldn't hold the threads of the engine
+#[tokio::test]
+async fn group_commit_frees_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(500);
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Interval(interval));
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
 
+    let started = Instant::now();
+    let set = {
+        let store = store.clone();
+        tokio::spawn(async move { store.set(b"key2".to_vec(), b"value2".to_vec()).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    assert!(started.elapsed() < interval);
+    set.await.unwrap()?;
+    assert!(started.elapsed() >= interval / 2);
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
+#[tokio::test]
+async fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
     // We only check concurrent get in this test, so we set sequentially here
     for i in 0..100 {
         store
-            .set(format!("key{}", i), format!("value{}", i))
-            .wait()
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .await
             .unwrap();
     }
 
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
-            }
+    let mut gets = Vec::new();
+    for thread_id in 0..100 {
+        for i in 0..100 {
+            let key_id = (i + thread_id) % 100;
+            let store = store.clone();
+            gets.push(tokio::spawn(async move {
+                let res = store.get(format!("key{}", key_id).into_bytes()).await;
+                assert_eq!(res.unwrap(), Some(format!("value{}", key_id).into_bytes()));
+            }));
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+    }
+    for get in gets {
+        get.await.unwrap();
+    }
 
     // reload from disk and test again
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
-            }
+    let mut gets = Vec::new();
+    for thread_id in 0..100 {
+        for i in 0..100 {
+            let key_id = (i + thread_id) % 100;
+            let store = store.clone();
+            gets.push(tokio::spawn(async move {
+                let res = store.get(format!("key{}", key_id).into_bytes()).await;
+                assert_eq!(res.unwrap(), Some(format!("value{}", key_id).into_bytes()));
+            }));
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+    }
+    for get in gets {
+        get.await.unwrap();
+    }
 
     Ok(())
 }
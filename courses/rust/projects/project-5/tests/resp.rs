@@ -0,0 +1,256 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsServer, Protocol, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Starts a RESP server with a new store in `temp_dir`.
+async fn start_server(
+    temp_dir: &TempDir,
+    addr: SocketAddr,
+    auth_token: Option<&str>,
+) -> Result<()> {
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let mut server = KvsServer::new(store).protocol(Protocol::Resp);
+    if let Some(token) = auth_token {
+        server = server.auth_token(token.to_owned());
+    }
+    tokio::spawn(server.run(addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    Ok(())
+}
+
+/// Encodes a command as an array of bulk strings, as Redis clients do.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    buf
+}
+
+/// Sends the input and checks the replies.
+async fn check(stream: &mut BufReader<TcpStream>, input: &[u8], expected: &str) -> Result<()> {
+    stream.get_mut().write_all(input).await?;
+    let mut replies = vec![0; expected.len()];
+    stream.read_exact(&mut replies).await?;
+    assert_eq!(String::from_utf8_lossy(&replies), expected);
+    Ok(())
+}
+
+/// Sends a `SCAN` command and returns the cursor and the keys in the reply.
+async fn scan(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<(String, Vec<String>)> {
+    stream.get_mut().write_all(&command(args)).await?;
+    let mut lines = Vec::new();
+    // the array header, the cursor and the header of the array of keys
+    for _ in 0..4 {
+        lines.push(read_line(stream).await?);
+    }
+    assert_eq!(lines[0], "*2");
+    let cursor = lines[2].clone();
+    let len: usize = lines[3][1..].parse().unwrap();
+    let mut keys = Vec::new();
+    for _ in 0..len {
+        read_line(stream).await?;
+        keys.push(read_line(stream).await?);
+    }
+    Ok((cursor, keys))
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    Ok(line.trim_end().to_owned())
+}
+
+// Redis clients should be able to get, set and remove keys
+#[tokio::test]
+async fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4014".parse().unwrap();
+    start_server(&temp_dir, addr, None).await?;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    check(&mut stream, &command(&["PING"]), "+PONG\r\n").await?;
+    check(&mut stream, &command(&["ping", "hi"]), "$2\r\nhi\r\n").await?;
+    check(&mut stream, b"PING\r\n", "+PONG\r\n").await?;
+    check(&mut stream, &command(&["SET", "key1", "value1"]), "+OK\r\n").await?;
+    check(&mut stream, &command(&["GET", "key1"]), "$6\r\nvalue1\r\n").await?;
+    check(&mut stream, &command(&["GET", "key2"]), "$-1\r\n").await?;
+    check(&mut stream, b"EXISTS key1 key2 key1\r\n", ":2\r\n").await?;
+    check(&mut stream, &command(&["DEL", "key1", "key2"]), ":1\r\n").await?;
+    check(&mut stream, &command(&["EXISTS", "key1"]), ":0\r\n").await?;
+
+    // pipelined commands are answered in order
+    let mut input = command(&["SET", "key1", "value2"]);
+    input.extend(command(&["SET", "key1", "value3"]));
+    input.extend(command(&["GET", "key1"]));
+    check(&mut stream, &input, "+OK\r\n+OK\r\n$6\r\nvalue3\r\n").await?;
+
+    check(
+        &mut stream,
+        &command(&["SET", "key2", "value", "PX", "100"]),
+        "+OK\r\n",
+    )
+    .await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    check(&mut stream, &command(&["GET", "key2"]), "$-1\r\n").await?;
+
+    check(
+        &mut stream,
+        &command(&["SET", "key2", "value", "EX", "0"]),
+        "-ERR invalid expire time in 'set' command\r\n",
+    )
+    .await?;
+    check(
+        &mut stream,
+        &command(&["SET", "key2", "value", "NX"]),
+        "-ERR syntax error\r\n",
+    )
+    .await?;
+    check(
+        &mut stream,
+        &command(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    )
+    .await?;
+    check(
+        &mut stream,
+        &command(&["INCR", "key1"]),
+        "-ERR unknown command 'INCR'\r\n",
+    )
+    .await?;
+
+    check(
+        &mut stream,
+        &command(&["AUTH", "secret"]),
+        "-ERR AUTH <password> called without any password configured for the default \
+         user. Are you sure your configuration is correct?\r\n",
+    )
+    .await?;
+
+    check(
+        &mut stream,
+        b"*1\r\n+PING\r\n",
+        "-ERR Protocol error: expected '$', got '+'\r\n",
+    )
+    .await?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// Commands split across reads should be parsed once complete, and bulk strings
+// not followed by a line break should be rejected
+#[tokio::test]
+async fn resp_partial_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4029".parse().unwrap();
+    start_server(&temp_dir, addr, None).await?;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    let value = "v".repeat(100_000);
+    let input = command(&["SET", "key1", &value]);
+    for chunk in input.chunks(7_000) {
+        stream.get_mut().write_all(chunk).await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    check(&mut stream, b"", "+OK\r\n").await?;
+    let expected = format!("${}\r\n{}\r\n", value.len(), value);
+    check(&mut stream, &command(&["GET", "key1"]), &expected).await?;
+
+    check(
+        &mut stream,
+        b"*1\r\n$4\r\nPINGxx*1\r\n$4\r\nPING\r\n",
+        "-ERR Protocol error: expected '\\r\\n' after bulk string\r\n",
+    )
+    .await?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// `SCAN` should iterate over all keys with cursors and filter them with `MATCH`
+#[tokio::test]
+async fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4015".parse().unwrap();
+    start_server(&temp_dir, addr, None).await?;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    let expected: Vec<_> = (0..25).map(|i| format!("key{:02}", i)).collect();
+    for key in &expected {
+        check(&mut stream, &command(&["SET", key, "value"]), "+OK\r\n").await?;
+    }
+    check(&mut stream, &command(&["SET", "other", "value"]), "+OK\r\n").await?;
+
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_owned();
+    loop {
+        let (next, page) = scan(&mut stream, &["SCAN", &cursor, "MATCH", "key*"]).await?;
+        assert!(page.len() <= 10);
+        keys.extend(page);
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    assert_eq!(keys, expected);
+
+    let (cursor, keys) = scan(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "key1?", "COUNT", "100"],
+    )
+    .await?;
+    assert_eq!(cursor, "0");
+    assert_eq!(keys, &expected[10..20]);
+    let (_, keys) = scan(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "*[^0-9]", "COUNT", "100"],
+    )
+    .await?;
+    assert_eq!(keys, vec!["other"]);
+
+    check(
+        &mut stream,
+        &command(&["SCAN", "12345"]),
+        "-ERR invalid cursor\r\n",
+    )
+    .await?;
+    Ok(())
+}
+
+// A RESP server with a token should only execute commands after `AUTH`
+#[tokio::test]
+async fn resp_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4016".parse().unwrap();
+    start_server(&temp_dir, addr, Some("secret")).await?;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    check(
+        &mut stream,
+        &command(&["GET", "key1"]),
+        "-NOAUTH Authentication required.\r\n",
+    )
+    .await?;
+    check(
+        &mut stream,
+        &command(&["AUTH", "wrong"]),
+        "-WRONGPASS invalid username-password pair\r\n",
+    )
+    .await?;
+    check(
+        &mut stream,
+        &command(&["AUTH", "default", "secret"]),
+        "+OK\r\n",
+    )
+    .await?;
+    check(&mut stream, &command(&["GET", "key1"]), "$-1\r\n").await?;
+    check(&mut stream, &command(&["QUIT"]), "+OK\r\n").await?;
+    Ok(())
+}
@@ -64,6 +64,17 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn instrumented_thread_pool_spawn_counter() -> Result<()> {
+    let pool = InstrumentedThreadPool::<SharedQueueThreadPool>::new(4)?;
+    spawn_counter(pool.clone())?;
+    let stats = pool.stats();
+    assert_eq!(stats.threads, 4);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.wait.count, 20);
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()